@@ -2,26 +2,37 @@
 
 use std::{
     borrow::Cow,
+    collections::HashMap,
     fmt,
     fmt::Display,
-    io::{self, Read, Write},
-    path::Path,
-    process::{Child, ChildStderr, Command, Stdio},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStderr, Command, ExitStatus, Stdio},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        mpsc, Arc, Mutex,
     },
+    thread,
+    time::Duration,
 };
 
+use crate::diagnostics::{Diagnostic, Severity};
+use crate::options::DiagnosticFormat;
+use crate::output_files::ReportLines;
+
 /// Represents the types of errors that may occur while using cc-rs.
-#[derive(Clone, Debug)]
-enum ErrorKind {
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
     /// Error occurred while performing I/O.
     IOError,
     /// Error occurred while using external tools (ie: invocation of compiler).
     ToolExecError,
     /// Error occurred due to missing external tools.
     ToolNotFound,
+    /// Error occurred due to conflicting or invalid builder settings.
+    InvalidConfiguration,
 }
 
 /// Represents an internal error that occurred, with an explanation.
@@ -34,12 +45,30 @@ pub struct Error {
 }
 
 impl Error {
-    fn new(kind: ErrorKind, message: impl Into<Cow<'static, str>>) -> Error {
+    pub(crate) fn new(kind: ErrorKind, message: impl Into<Cow<'static, str>>) -> Error {
         Error {
             kind,
             message: message.into(),
         }
     }
+
+    /// Returns what kind of error occurred, for example to tell a missing kotlinc
+    /// ([`ErrorKind::ToolNotFound`]) apart from a failed compile ([`ErrorKind::ToolExecError`]).
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Prefixes the message with what was being done when the error occurred.
+    pub(crate) fn context(mut self, context: impl Display) -> Error {
+        self.message = format!("{}: {}", context, self.message).into();
+        self
+    }
+
+    /// Appends further explanation to the message, on a line of its own.
+    pub(crate) fn with_note(mut self, note: impl Display) -> Error {
+        self.message = format!("{}\n{}", self.message, note).into();
+        self
+    }
 }
 
 impl From<io::Error> for Error {
@@ -56,20 +85,68 @@ impl Display for Error {
 
 impl std::error::Error for Error {}
 
+/// Environment variables enabling debug output. `CC_ENABLE_DEBUG_OUTPUT` is still honored for
+/// compatibility with the cc-rs lineage of this code.
+const DEBUG_VARS: [&str; 2] = ["KOTLIN_RS_DEBUG", "CC_ENABLE_DEBUG_OUTPUT"];
+
+/// Returns whether any of the [`DEBUG_VARS`] is set, reading variables through `env`.
+fn debug_enabled(env: impl Fn(&str) -> Option<std::ffi::OsString>) -> bool {
+    DEBUG_VARS.iter().any(|var| env(var).is_some())
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) struct CargoOutput {
     pub(crate) metadata: bool,
     pub(crate) warnings: bool,
     pub(crate) debug: bool,
     pub(crate) output: OutputKind,
+    /// Directory that paths in the compiler's output are made relative to.
+    pub(crate) relative_to: Option<PathBuf>,
+    /// How forwarded compiler diagnostics are printed.
+    pub(crate) diagnostic_format: DiagnosticFormat,
+    /// Decides which lines of compiler output are forwarded.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) line_filter: Option<LineFilter>,
+    /// File that all compiler output is appended to.
+    pub(crate) log_file: Option<PathBuf>,
+    /// Whether repeated diagnostics are forwarded only once.
+    pub(crate) dedupe_warnings: bool,
+    /// Whether the compiler is stopped as soon as it reports an error.
+    pub(crate) fail_fast: bool,
+    /// How often a line is printed while a command runs, if at all.
+    pub(crate) heartbeat: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(skip))]
     checked_dbg_var: Arc<AtomicBool>,
 }
 
+/// A predicate deciding whether a line of compiler output is forwarded.
+#[derive(Clone)]
+pub(crate) struct LineFilter(pub(crate) Arc<dyn Fn(&str) -> bool + Send + Sync>);
+
+impl LineFilter {
+    /// Returns whether `line` passes `filter`, which is the case for every line without one.
+    fn keeps(filter: Option<&LineFilter>, line: &[u8]) -> bool {
+        filter.is_none_or(|filter| (filter.0)(&String::from_utf8_lossy(line)))
+    }
+}
+
+impl fmt::Debug for LineFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LineFilter")
+    }
+}
+
 /// Different strategies for handling compiler output (to stdout)
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub(crate) enum OutputKind {
     /// Forward the output to this process' stdout (Stdio::inherit)
     Forward,
+    /// Discard the output (Stdio::null)
+    Discard,
+    /// Pipe the output so it can be read by this process (Stdio::piped)
+    Capture,
 }
 
 impl CargoOutput {
@@ -79,21 +156,70 @@ impl CargoOutput {
             metadata: true,
             warnings: true,
             output: OutputKind::Forward,
-            debug: std::env::var_os("CC_ENABLE_DEBUG_OUTPUT").is_some(),
+            relative_to: None,
+            diagnostic_format: DiagnosticFormat::CargoWarning,
+            line_filter: None,
+            log_file: None,
+            dedupe_warnings: false,
+            fail_fast: false,
+            heartbeat: None,
+            debug: debug_enabled(|var| std::env::var_os(var)),
             checked_dbg_var: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    pub(crate) fn print_metadata(&self, s: &dyn Display) {
+        if self.metadata {
+            println!("{}", s);
+        }
+    }
+
+    pub(crate) fn print_warning(&self, arg: &dyn Display) {
+        if self.warnings {
+            println!("cargo:warning={}", arg);
+        }
+    }
+
     pub(crate) fn print_debug(&self, arg: &dyn Display) {
         if self.metadata && !self.checked_dbg_var.load(Ordering::Relaxed) {
             self.checked_dbg_var.store(true, Ordering::Relaxed);
-            println!("cargo:rerun-if-env-changed=CC_ENABLE_DEBUG_OUTPUT");
+            for var in DEBUG_VARS {
+                println!("cargo:rerun-if-env-changed={}", var);
+            }
         }
         if self.debug {
             println!("{}", arg);
         }
     }
 
+    /// The bytes to strip from paths below [`CargoOutput::relative_to`], including the trailing
+    /// separator.
+    fn path_prefix(&self) -> Option<Vec<u8>> {
+        self.relative_to.as_ref().map(|base| {
+            let mut prefix = base.as_os_str().to_string_lossy().into_owned();
+            if !prefix.ends_with(std::path::MAIN_SEPARATOR) {
+                prefix.push(std::path::MAIN_SEPARATOR);
+            }
+            prefix.into_bytes()
+        })
+    }
+
+    /// Opens [`CargoOutput::log_file`] for appending, warning instead of failing if it can't be.
+    fn open_log(&self) -> Option<File> {
+        let path = self.log_file.as_ref()?;
+        match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                self.print_warning(&format_args!(
+                    "Failed to open log file {}: {}",
+                    path.display(),
+                    e
+                ));
+                None
+            }
+        }
+    }
+
     fn stdio_for_warnings(&self) -> Stdio {
         if self.warnings {
             Stdio::piped()
@@ -105,12 +231,25 @@ impl CargoOutput {
     fn stdio_for_output(&self) -> Stdio {
         match self.output {
             OutputKind::Forward => Stdio::inherit(),
+            OutputKind::Discard => Stdio::null(),
+            OutputKind::Capture => Stdio::piped(),
         }
     }
 }
 
 pub(crate) struct StderrForwarder {
     inner: Option<(ChildStderr, Vec<u8>)>,
+    captured: Option<Vec<u8>>,
+    lines: Option<CapturedLines>,
+    forward: bool,
+    path_prefix: Option<Vec<u8>>,
+    format: DiagnosticFormat,
+    filter: Option<LineFilter>,
+    log: Option<File>,
+    repeats: Option<Repeats>,
+    report: Option<ReportLines>,
+    fail_fast: bool,
+    failed: bool,
 }
 
 const MIN_BUFFER_CAPACITY: usize = 100;
@@ -122,10 +261,65 @@ impl StderrForwarder {
                 .stderr
                 .take()
                 .map(|stderr| (stderr, Vec::with_capacity(MIN_BUFFER_CAPACITY))),
+            captured: None,
+            lines: None,
+            forward: true,
+            path_prefix: None,
+            format: DiagnosticFormat::CargoWarning,
+            filter: None,
+            log: None,
+            repeats: None,
+            report: None,
+            fail_fast: false,
+            failed: false,
         }
     }
 
-    fn forward_available(&mut self) -> bool {
+    /// Treats stderr as compiler diagnostics: paths are shortened to be relative to
+    /// [`CargoOutput::relative_to`], if set, and lines are forwarded in
+    /// [`CargoOutput::diagnostic_format`] unless [`CargoOutput::line_filter`] rejects them. Every
+    /// line is also appended to [`CargoOutput::log_file`], if set, but the report of
+    /// `-Xreport-output-files` is not forwarded. With [`CargoOutput::fail_fast`], reading stops
+    /// after the first error diagnostic.
+    pub(crate) fn diagnostics(mut self, cargo_output: &CargoOutput) -> Self {
+        self.path_prefix = cargo_output.path_prefix();
+        self.format = cargo_output.diagnostic_format;
+        self.filter.clone_from(&cargo_output.line_filter);
+        self.log = cargo_output.open_log();
+        self.repeats = cargo_output.dedupe_warnings.then(Repeats::default);
+        self.report = Some(ReportLines::default());
+        self.fail_fast = cargo_output.fail_fast;
+        self
+    }
+
+    /// Stops forwarding to cargo, so stderr is only kept if [`StderrForwarder::capture`] is used.
+    pub(crate) fn silent(mut self) -> Self {
+        self.forward = false;
+        self
+    }
+
+    /// Keeps a copy of everything forwarded, retrievable with [`StderrForwarder::take_captured`].
+    pub(crate) fn capture(mut self) -> Self {
+        self.captured = Some(Vec::new());
+        self
+    }
+
+    /// Records every line in `lines` as well, tagged as [`Stream::Stderr`].
+    fn record_into(mut self, lines: &CapturedLines) -> Self {
+        self.lines = Some(lines.clone());
+        self
+    }
+
+    pub(crate) fn take_captured(&mut self) -> Vec<u8> {
+        self.captured.take().unwrap_or_default()
+    }
+
+    /// Returns whether reading was stopped early because of an error diagnostic.
+    pub(crate) fn failed_fast(&self) -> bool {
+        self.failed
+    }
+
+    fn forward_available(&mut self, mut sink: Option<&mut dyn Write>) -> bool {
         if let Some((stderr, buffer)) = self.inner.as_mut() {
             loop {
                 let old_data_end = buffer.len();
@@ -136,7 +330,7 @@ impl StderrForwarder {
                 buffer.reserve(to_reserve);
 
                 // Safety: stderr.read only writes to the spare part of the buffer, it never reads from it
-                match stderr
+                let res = match stderr
                     .read(unsafe { &mut *(buffer.spare_capacity_mut() as *mut _ as *mut [u8]) })
                 {
                     Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
@@ -155,25 +349,68 @@ impl StderrForwarder {
                             // Only forward complete lines, leave the rest in the buffer.
                             if let Some((b'\n', line)) = line.split_last() {
                                 consumed += line.len() + 1;
-                                write_warning(line);
+                                let line = shorten_paths(
+                                    strip_carriage_return(line),
+                                    self.path_prefix.as_deref(),
+                                );
+                                write_log(self.log.as_mut(), &line);
+                                self.failed |= self.fail_fast && is_error(&line);
+                                if self.forward
+                                    && !ReportLines::contains(self.report.as_mut(), &line)
+                                    && LineFilter::keeps(self.filter.as_ref(), &line)
+                                    && Repeats::first(self.repeats.as_mut(), &line)
+                                {
+                                    emit_line(&line, self.format, reborrow(&mut sink));
+                                }
+                                if let Some(captured) = self.captured.as_mut() {
+                                    captured.extend_from_slice(&line);
+                                    captured.push(b'\n');
+                                }
+                                if let Some(lines) = &self.lines {
+                                    lines.push(Stream::Stderr, &line);
+                                }
                             }
                         }
                         buffer.drain(..consumed);
-                    }
-                    res => {
-                        // End of stream: flush remaining data and bail.
-                        if old_data_end > 0 {
-                            write_warning(&buffer[..old_data_end]);
+                        if !self.failed {
+                            continue;
                         }
-                        if let Err(err) = res {
-                            write_warning(
-                                format!("Failed to read from child stderr: {err}").as_bytes(),
-                            );
-                        }
-                        self.inner.take();
-                        break true;
+                        // Stop at the first error, but still forward what was already read
+                        Ok(0)
+                    }
+                    res => res,
+                };
+
+                // End of stream: flush remaining data and bail.
+                if !buffer.is_empty() {
+                    let line =
+                        shorten_paths(strip_carriage_return(buffer), self.path_prefix.as_deref());
+                    write_log(self.log.as_mut(), &line);
+                    if self.forward
+                        && !ReportLines::contains(self.report.as_mut(), &line)
+                        && LineFilter::keeps(self.filter.as_ref(), &line)
+                        && Repeats::first(self.repeats.as_mut(), &line)
+                    {
+                        emit_line(&line, self.format, reborrow(&mut sink));
+                    }
+                    if let Some(captured) = self.captured.as_mut() {
+                        captured.extend_from_slice(&line);
+                        captured.push(b'\n');
                     }
+                    if let Some(lines) = &self.lines {
+                        lines.push(Stream::Stderr, &line);
+                    }
+                }
+                if let Some(repeats) = self.repeats.take() {
+                    for summary in repeats.summaries() {
+                        emit_line(&summary, self.format, reborrow(&mut sink));
+                    }
+                }
+                if let Err(err) = res {
+                    write_warning(format!("Failed to read from child stderr: {err}").as_bytes());
                 }
+                self.inner.take();
+                break true;
             }
         } else {
             true
@@ -181,11 +418,346 @@ impl StderrForwarder {
     }
 
     fn forward_all(&mut self) {
-        let forward_result = self.forward_available();
+        self.forward_all_into(None);
+    }
+
+    /// Forwards everything to `sink` instead of this process' stdout, if given.
+    fn forward_all_into(&mut self, sink: Option<&mut dyn Write>) {
+        let forward_result = self.forward_available(sink);
         assert!(forward_result, "Should have consumed all data");
     }
 }
 
+/// Prints a line every [`CargoOutput::heartbeat`] from a separate thread until dropped, so long
+/// commands that print nothing don't look stuck, even when warnings are disabled.
+struct Heartbeat {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Heartbeat {
+    /// Starts printing to stdout unless no interval is set or the output of commands is not
+    /// forwarded.
+    fn start(cargo_output: &CargoOutput) -> Self {
+        let interval = cargo_output
+            .heartbeat
+            .filter(|_| matches!(cargo_output.output, OutputKind::Forward));
+        let Some(interval) = interval else {
+            return Self {
+                stop: None,
+                thread: None,
+            };
+        };
+
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = Self::spawn(interval, io::stdout(), move || {
+            matches!(
+                stopped.recv_timeout(interval),
+                Err(mpsc::RecvTimeoutError::Timeout)
+            )
+        });
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Spawns the thread printing to `out`. `wait` blocks until the next line is due and returns
+    /// `false` once the heartbeat should stop.
+    fn spawn<W, F>(interval: Duration, mut out: W, mut wait: F) -> thread::JoinHandle<()>
+    where
+        W: Write + Send + 'static,
+        F: FnMut() -> bool + Send + 'static,
+    {
+        thread::spawn(move || {
+            let mut elapsed = Duration::ZERO;
+            while wait() {
+                elapsed += interval;
+                let _ = writeln!(out, "still compiling ({}s elapsed)", elapsed.as_secs());
+            }
+        })
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        // Disconnecting the channel wakes the thread up
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Tracks which diagnostics were already forwarded, so repeats of them can be summarized
+/// instead. Lines that aren't diagnostics, such as source excerpts, are only dropped together
+/// with the repeated diagnostic they follow.
+#[derive(Default)]
+struct Repeats {
+    counts: HashMap<Vec<u8>, usize>,
+    order: Vec<Vec<u8>>,
+    dropping: bool,
+}
+
+impl Repeats {
+    /// The number of distinct diagnostics tracked, beyond which new ones are always forwarded.
+    const MAX_TRACKED: usize = 10_000;
+
+    /// Returns whether `line` should be forwarded, which is the case unless it is a diagnostic
+    /// that was forwarded before or belongs to one. Always true without tracking.
+    fn first(repeats: Option<&mut Repeats>, line: &[u8]) -> bool {
+        let Some(repeats) = repeats else {
+            return true;
+        };
+        if Diagnostic::parse(&String::from_utf8_lossy(line)).is_none() {
+            return !repeats.dropping;
+        }
+
+        repeats.dropping = false;
+        if let Some(count) = repeats.counts.get_mut(line) {
+            *count += 1;
+            repeats.dropping = true;
+            return false;
+        }
+        if repeats.counts.len() < Self::MAX_TRACKED {
+            repeats.counts.insert(line.to_vec(), 0);
+            repeats.order.push(line.to_vec());
+        }
+        true
+    }
+
+    /// Returns a `<line> ... (N more identical)` summary for every repeated diagnostic, in the
+    /// order they were first seen.
+    fn summaries(mut self) -> Vec<Vec<u8>> {
+        self.order
+            .into_iter()
+            .filter_map(|line| {
+                let count = self.counts.remove(&line)?;
+                let mut summary = line;
+                summary.extend_from_slice(format!(" ... ({} more identical)", count).as_bytes());
+                (count > 0).then_some(summary)
+            })
+            .collect()
+    }
+}
+
+/// Reads the stdout of a child on a separate thread, so it can be drained while stderr is
+/// forwarded, optionally passing each line on to this process' stdout. Every line is recorded in
+/// the given [`CapturedLines`], tagged as [`Stream::Stdout`].
+struct StdoutCapture {
+    reader: Option<thread::JoinHandle<()>>,
+}
+
+impl StdoutCapture {
+    fn new(child: &mut Child, cargo_output: &CargoOutput, lines: &CapturedLines) -> Self {
+        let lines = lines.clone();
+        let forward = matches!(cargo_output.output, OutputKind::Forward);
+        let path_prefix = cargo_output.path_prefix();
+        let filter = cargo_output.line_filter.clone();
+        let mut log = cargo_output.open_log();
+        let reader = child.stdout.take().map(|stdout| {
+            thread::spawn(move || {
+                for line in BufReader::new(stdout).split(b'\n') {
+                    let Ok(line) = line else {
+                        break;
+                    };
+                    let line = shorten_paths(strip_carriage_return(&line), path_prefix.as_deref());
+                    write_log(log.as_mut(), &line);
+                    if forward && LineFilter::keeps(filter.as_ref(), &line) {
+                        let stdout = io::stdout();
+                        let mut stdout = stdout.lock();
+                        stdout.write_all(&line).unwrap();
+                        stdout.write_all(b"\n").unwrap();
+                    }
+                    lines.push(Stream::Stdout, &line);
+                }
+            })
+        });
+        Self { reader }
+    }
+
+    fn join(self) {
+        if let Some(reader) = self.reader {
+            let _ = reader.join();
+        }
+    }
+}
+
+/// The stream of a child that a captured line was written to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// The lines read from both streams of a child, in the order they were read. Shared between the
+/// threads reading the streams.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CapturedLines(Arc<Mutex<Vec<(Stream, String)>>>);
+
+impl CapturedLines {
+    fn push(&self, stream: Stream, line: &[u8]) {
+        let line = String::from_utf8_lossy(line).into_owned();
+        self.0.lock().unwrap().push((stream, line));
+    }
+
+    fn take(&self) -> Vec<(Stream, String)> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// Everything a child wrote to stdout and stderr, with the stream each line was written to.
+#[derive(Debug, Default)]
+pub(crate) struct CapturedOutput {
+    lines: Vec<(Stream, String)>,
+    /// Whether the child was stopped at its first error because of [`CargoOutput::fail_fast`].
+    pub(crate) stopped_early: bool,
+}
+
+impl CapturedOutput {
+    /// Returns the lines of both streams interleaved in the order they were written, each
+    /// tagged with its stream.
+    pub(crate) fn merged(&self) -> &[(Stream, String)] {
+        &self.lines
+    }
+
+    /// Returns the lines written to `stream`, in order.
+    pub(crate) fn lines_of(&self, stream: Stream) -> impl Iterator<Item = &str> {
+        self.lines
+            .iter()
+            .filter(move |(origin, _)| *origin == stream)
+            .map(|(_, line)| line.as_str())
+    }
+
+    /// Returns the lines of [`CapturedOutput::merged`] as text, for parsers that don't care
+    /// which stream a line was written to.
+    pub(crate) fn text(&self) -> String {
+        self.merged()
+            .iter()
+            .map(|(_, line)| format!("{}\n", line))
+            .collect()
+    }
+}
+
+/// Returns whether `line` is an error diagnostic.
+fn is_error(line: &[u8]) -> bool {
+    Diagnostic::parse(&String::from_utf8_lossy(line))
+        .is_some_and(|diagnostic| diagnostic.severity == Severity::Error)
+}
+
+/// Removes the `\r` that precedes the `\n` of lines written with Windows line endings.
+fn strip_carriage_return(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Removes every occurrence of `prefix` from `line`, turning the absolute paths kotlinc prints
+/// into relative ones.
+fn shorten_paths<'a>(line: &'a [u8], prefix: Option<&[u8]>) -> Cow<'a, [u8]> {
+    let Some(prefix) =
+        prefix.filter(|prefix| line.windows(prefix.len()).any(|window| window == *prefix))
+    else {
+        return Cow::Borrowed(line);
+    };
+
+    let mut shortened = Vec::with_capacity(line.len());
+    let mut rest = line;
+    while !rest.is_empty() {
+        if rest.starts_with(prefix) {
+            rest = &rest[prefix.len()..];
+        } else {
+            shortened.push(rest[0]);
+            rest = &rest[1..];
+        }
+    }
+    Cow::Owned(shortened)
+}
+
+/// Reborrows an optional sink for a single call, so it can be used again afterwards.
+pub(crate) fn reborrow<'a>(sink: &'a mut Option<&mut dyn Write>) -> Option<&'a mut dyn Write> {
+    sink.as_mut().map(|sink| &mut **sink as &mut dyn Write)
+}
+
+/// Writes a line of compiler output as-is to `sink` if given, and prints it in the given format
+/// otherwise.
+fn emit_line(line: &[u8], format: DiagnosticFormat, sink: Option<&mut dyn Write>) {
+    match sink {
+        Some(sink) => {
+            let _ = sink.write_all(line).and_then(|()| sink.write_all(b"\n"));
+        }
+        None => write_diagnostic(line, format),
+    }
+}
+
+/// Prints a line of compiler output in the given format. Lines that aren't diagnostics are
+/// printed as-is by the formats other than [`DiagnosticFormat::CargoWarning`].
+fn write_diagnostic(line: &[u8], format: DiagnosticFormat) {
+    let annotation = match format {
+        DiagnosticFormat::CargoWarning => return write_warning(line),
+        DiagnosticFormat::Plain => None,
+        DiagnosticFormat::GithubActions => Diagnostic::parse(&String::from_utf8_lossy(line))
+            .map(|diagnostic| github_annotation(&diagnostic)),
+    };
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    match annotation {
+        Some(annotation) => stdout.write_all(annotation.as_bytes()).unwrap(),
+        None => stdout.write_all(line).unwrap(),
+    }
+    stdout.write_all(b"\n").unwrap();
+}
+
+/// Formats a diagnostic as a GitHub Actions workflow command, such as
+/// `::error file=src/A.kt,line=3,col=5::Unresolved reference: x`.
+fn github_annotation(diagnostic: &Diagnostic) -> String {
+    fn escape_data(s: &str) -> String {
+        s.replace('%', "%25")
+            .replace('\r', "%0D")
+            .replace('\n', "%0A")
+    }
+    fn escape_property(s: &str) -> String {
+        escape_data(s).replace(':', "%3A").replace(',', "%2C")
+    }
+
+    let command = match diagnostic.severity {
+        Severity::Error => "error",
+        Severity::Warning => "warning",
+        Severity::Info => "notice",
+    };
+
+    let mut properties = vec![];
+    if let Some(file) = &diagnostic.file {
+        properties.push(format!("file={}", escape_property(&file.to_string_lossy())));
+    }
+    if let Some(line) = diagnostic.line {
+        properties.push(format!("line={}", line));
+    }
+    if let Some(column) = diagnostic.column {
+        properties.push(format!("col={}", column));
+    }
+    if let Some(id) = &diagnostic.id {
+        properties.push(format!("title={}", escape_property(id)));
+    }
+
+    let mut annotation = format!("::{}", command);
+    if !properties.is_empty() {
+        annotation.push(' ');
+        annotation.push_str(&properties.join(","));
+    }
+    annotation.push_str("::");
+    annotation.push_str(&escape_data(&diagnostic.message));
+    annotation
+}
+
+fn write_log(log: Option<&mut File>, line: &[u8]) {
+    if let Some(log) = log {
+        // A single write per line, so lines of stdout and stderr don't interleave
+        let mut entry = line.to_vec();
+        entry.push(b'\n');
+        let _ = log.write_all(&entry);
+    }
+}
+
 fn write_warning(line: &[u8]) {
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
@@ -200,17 +772,15 @@ fn wait_on_child(
     child: &mut Child,
     cargo_output: &CargoOutput,
 ) -> Result<(), Error> {
-    StderrForwarder::new(child).forward_all();
-
     let status = match child.wait() {
         Ok(s) => s,
         Err(e) => {
             return Err(Error::new(
                 ErrorKind::ToolExecError,
                 format!(
-                    "Failed to wait on spawned child process, command {:?} with args {}: {}.",
-                    cmd,
+                    "Failed to wait on spawned child process {}, command `{}`: {}.",
                     program.display(),
+                    display_command(cmd),
                     e
                 ),
             ));
@@ -225,10 +795,10 @@ fn wait_on_child(
         Err(Error::new(
             ErrorKind::ToolExecError,
             format!(
-                "Command {:?} with args {} did not execute successfully (status code {}).",
-                cmd,
+                "{} did not execute successfully (status code {}), command `{}`.",
                 program.display(),
-                status
+                status,
+                display_command(cmd)
             ),
         ))
     }
@@ -242,9 +812,124 @@ pub(crate) fn run(
     let program = program.as_ref();
 
     let mut child = spawn(cmd, program, cargo_output)?;
+    StderrForwarder::new(&mut child).forward_all();
     wait_on_child(cmd, program, &mut child, cargo_output)
 }
 
+/// Runs the command, forwarding its stderr and stdout as configured, and returns everything it
+/// wrote to either regardless of whether it succeeded. Some kotlinc versions report errors on
+/// stdout, so both streams have to be looked at.
+///
+/// If `sink` is given, the output is forwarded to it instead: stderr line by line as it is
+/// written, and stdout once the command has finished.
+pub(crate) fn run_and_capture_output(
+    cmd: &mut Command,
+    program: impl AsRef<Path>,
+    cargo_output: &CargoOutput,
+    mut sink: Option<&mut dyn Write>,
+) -> (Result<(), Error>, CapturedOutput) {
+    let program = program.as_ref();
+
+    // Both streams have to be piped to be captured, even if they would otherwise be discarded
+    let mut captured_cargo_output = cargo_output.clone();
+    captured_cargo_output.warnings = true;
+    captured_cargo_output.output = OutputKind::Capture;
+    // kotlinc is a script that runs java as a child of its own, which has to be stopped as well.
+    // Outside the terminal's foreground process group, kotlinc no longer receives the SIGINT of
+    // a Ctrl-C, so it only stops once it next writes to the closed pipes of this process.
+    #[cfg(unix)]
+    if cargo_output.fail_fast {
+        std::os::unix::process::CommandExt::process_group(cmd, 0);
+    }
+    let mut child = match spawn(cmd, program, &captured_cargo_output) {
+        Ok(child) => child,
+        Err(e) => return (Err(e), CapturedOutput::default()),
+    };
+    let heartbeat = Heartbeat::start(cargo_output);
+
+    // Stdout is read on another thread, which can't borrow the sink
+    let mut stdout_cargo_output = cargo_output.clone();
+    if sink.is_some() {
+        stdout_cargo_output.output = OutputKind::Discard;
+    }
+    let lines = CapturedLines::default();
+    let stdout = StdoutCapture::new(&mut child, &stdout_cargo_output, &lines);
+    let mut forwarder = StderrForwarder::new(&mut child)
+        .record_into(&lines)
+        .diagnostics(cargo_output);
+    if !cargo_output.warnings && sink.is_none() {
+        forwarder = forwarder.silent();
+    }
+    forwarder.forward_all_into(reborrow(&mut sink));
+    if forwarder.failed_fast() {
+        kill_process_group(&mut child);
+    }
+    stdout.join();
+    let output = CapturedOutput {
+        lines: lines.take(),
+        stopped_early: forwarder.failed_fast(),
+    };
+    if let Some(sink) = sink {
+        for line in output.lines_of(Stream::Stdout) {
+            let line = line.as_bytes();
+            if !line.is_empty() && LineFilter::keeps(cargo_output.line_filter.as_ref(), line) {
+                emit_line(line, cargo_output.diagnostic_format, Some(&mut *sink));
+            }
+        }
+    }
+    let status = wait_on_child(cmd, program, &mut child, cargo_output);
+    drop(heartbeat);
+    if output.stopped_early {
+        return (
+            Err(Error::new(
+                ErrorKind::ToolExecError,
+                format!(
+                    "{} was stopped after reporting an error, command `{}`.",
+                    program.display(),
+                    display_command(cmd)
+                ),
+            )),
+            output,
+        );
+    }
+    (status, output)
+}
+
+/// Kills `child` together with the processes it started, which on Unix share the process group
+/// `child` was spawned into. Elsewhere, only `child` itself is killed.
+fn kill_process_group(child: &mut Child) {
+    #[cfg(unix)]
+    {
+        // Safety: `kill` has no memory safety requirements, and a negative pid signals the
+        // process group led by `child`
+        unsafe {
+            libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+        }
+    }
+    let _ = child.kill();
+}
+
+/// Runs the command without forwarding anything to cargo and returns what it wrote to stderr.
+pub(crate) fn run_silently_and_capture_stderr(
+    cmd: &mut Command,
+    program: impl AsRef<Path>,
+    cargo_output: &CargoOutput,
+) -> Result<Vec<u8>, Error> {
+    let program = program.as_ref();
+
+    // Stderr has to be piped to be captured, even if warnings are disabled
+    let mut captured_cargo_output = cargo_output.clone();
+    captured_cargo_output.warnings = true;
+    let mut child = spawn(cmd, program, &captured_cargo_output)?;
+
+    let mut forwarder = StderrForwarder::new(&mut child).silent().capture();
+    forwarder.forward_all();
+    let stderr = forwarder.take_captured();
+    wait_on_child(cmd, program, &mut child, cargo_output)?;
+
+    Ok(stderr)
+}
+
 pub(crate) fn spawn(
     cmd: &mut Command,
     program: &Path,
@@ -260,7 +945,7 @@ pub(crate) fn spawn(
         }
     }
 
-    cargo_output.print_debug(&format_args!("running: {:?}", cmd));
+    cargo_output.print_debug(&format_args!("running: {}", display_command(cmd)));
 
     let cmd = ResetStderr(cmd);
     let child = cmd
@@ -268,33 +953,263 @@ pub(crate) fn spawn(
         .stderr(cargo_output.stdio_for_warnings())
         .stdout(cargo_output.stdio_for_output())
         .spawn();
-    match child {
-        Ok(child) => Ok(child),
-        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-            let extra = if cfg!(windows) {
-                " (see https://docs.rs/cc/latest/cc/#compile-time-requirements \
+    child.map_err(|e| spawn_error(cmd.0, program, e))
+}
+
+/// Runs the command with stdout and stderr inherited from this process, returning its exit
+/// status without treating an unsuccessful status as an error.
+pub(crate) fn run_inherited(
+    cmd: &mut Command,
+    program: &Path,
+    cargo_output: &CargoOutput,
+) -> Result<ExitStatus, Error> {
+    cargo_output.print_debug(&format_args!("running: {}", display_command(cmd)));
+
+    cmd.stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .map_err(|e| spawn_error(cmd, program, e))
+}
+
+/// Renders a command as a line that can be pasted into a POSIX shell, quoting the program and
+/// any argument that contains characters the shell would interpret.
+pub(crate) fn display_command(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| shell_quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub(crate) fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_-./=:,@+%".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+fn spawn_error(cmd: &Command, program: &Path, e: io::Error) -> Error {
+    if e.kind() == io::ErrorKind::NotFound {
+        let extra = if cfg!(windows) {
+            " (see https://docs.rs/cc/latest/cc/#compile-time-requirements \
 for help)"
-            } else {
-                ""
-            };
-            Err(Error::new(
-                ErrorKind::ToolNotFound,
-                format!(
-                    "Failed to find tool. Is `{}` installed?{}",
-                    program.display(),
-                    extra
-                ),
-            ))
-        }
-        Err(e) => Err(Error::new(
+        } else {
+            ""
+        };
+        Error::new(
+            ErrorKind::ToolNotFound,
+            format!(
+                "Failed to find tool. Is `{}` installed?{}",
+                program.display(),
+                extra
+            ),
+        )
+    } else {
+        Error::new(
             ErrorKind::ToolExecError,
             format!(
-                "Command {:?} with args {} failed to start: {:?}",
-                cmd.0,
+                "{} failed to start, command `{}`: {:?}",
                 program.display(),
+                display_command(cmd),
                 e
             ),
-        )),
+        )
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_quote_quotes_unsafe_arguments() {
+        assert_eq!(shell_quote("-Xflag=a,b"), "-Xflag=a,b");
+        assert_eq!(
+            shell_quote("/tmp/out dir/lib.jar"),
+            "'/tmp/out dir/lib.jar'"
+        );
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn display_command_renders_a_shell_line() {
+        let mut cmd = Command::new("kotlinc-jvm");
+        cmd.arg("-d").arg("my out").arg("Main.kt");
+        assert_eq!(display_command(&cmd), "kotlinc-jvm -d 'my out' Main.kt");
+    }
+
+    #[test]
+    fn shorten_paths_strips_the_prefix() {
+        let line = b"/work/src/Main.kt:1:5: warning: see /work/src/Util.kt and /other/Main.kt";
+        assert_eq!(
+            &*shorten_paths(line, Some(b"/work/")),
+            b"src/Main.kt:1:5: warning: see src/Util.kt and /other/Main.kt"
+        );
+        assert_eq!(&*shorten_paths(line, None), line);
+        assert!(matches!(
+            shorten_paths(line, Some(b"/elsewhere/")),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn github_annotation_formats_diagnostics() {
+        let diagnostic = Diagnostic::parse(
+            "src/A.kt:3:5: error: [UNRESOLVED_REFERENCE] Unresolved reference: x",
+        )
+        .unwrap();
+        assert_eq!(
+            github_annotation(&diagnostic),
+            "::error file=src/A.kt,line=3,col=5,title=UNRESOLVED_REFERENCE::Unresolved reference: x"
+        );
+
+        let diagnostic = Diagnostic::parse("warning: 100% of sources, see a,b").unwrap();
+        assert_eq!(
+            github_annotation(&diagnostic),
+            "::warning::100%25 of sources, see a,b"
+        );
+
+        let diagnostic = Diagnostic::parse("info: kotlinc started").unwrap();
+        assert_eq!(github_annotation(&diagnostic), "::notice::kotlinc started");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn forwarded_lines_drop_carriage_returns() {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(r"printf 'warning: one\r\nwarning: two\r\nlast\r' >&2")
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut forwarder = StderrForwarder::new(&mut child).capture();
+        let mut forwarded = Vec::new();
+        forwarder.forward_all_into(Some(&mut forwarded));
+        child.wait().unwrap();
+
+        assert_eq!(forwarded, b"warning: one\nwarning: two\nlast\n");
+        assert_eq!(forwarder.take_captured(), forwarded);
+        assert_eq!(strip_carriage_return(b"line"), b"line");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn repeated_diagnostics_are_collapsed() {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(
+                "for i in 1 2 3; do
+                    echo 'A.kt:1:1: warning: missing annotation' >&2
+                    echo '    @Foo' >&2
+                done
+                echo 'A.kt:2:1: warning: unused variable' >&2",
+            )
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut cargo_output = CargoOutput::new();
+        cargo_output.dedupe_warnings = true;
+        let mut forwarder = StderrForwarder::new(&mut child).diagnostics(&cargo_output);
+        let mut forwarded = Vec::new();
+        forwarder.forward_all_into(Some(&mut forwarded));
+        child.wait().unwrap();
+
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "A.kt:1:1: warning: missing annotation\n    @Foo\n\
+             A.kt:2:1: warning: unused variable\n\
+             A.kt:1:1: warning: missing annotation ... (2 more identical)\n"
+        );
+    }
+
+    /// A writer whose output stays readable after it was moved to another thread.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn heartbeat_prints_a_line_per_tick() {
+        let out = SharedBuffer::default();
+        let (tick, ticks) = mpsc::channel::<()>();
+        let thread = Heartbeat::spawn(Duration::from_secs(30), out.clone(), move || {
+            ticks.recv().is_ok()
+        });
+        for _ in 0..3 {
+            tick.send(()).unwrap();
+        }
+        drop(tick);
+        thread.join().unwrap();
+
+        assert_eq!(
+            String::from_utf8(out.0.lock().unwrap().clone()).unwrap(),
+            "still compiling (30s elapsed)\n\
+             still compiling (60s elapsed)\n\
+             still compiling (90s elapsed)\n"
+        );
+    }
+
+    #[test]
+    fn heartbeat_only_runs_when_output_is_forwarded() {
+        let mut cargo_output = CargoOutput::new();
+        assert!(Heartbeat::start(&cargo_output).thread.is_none());
+
+        cargo_output.heartbeat = Some(Duration::from_secs(60));
+        cargo_output.warnings = false;
+        assert!(Heartbeat::start(&cargo_output).thread.is_some());
+
+        cargo_output.output = OutputKind::Discard;
+        assert!(Heartbeat::start(&cargo_output).thread.is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn captured_output_keeps_the_order_of_both_streams() {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(
+            "echo one; sleep 0.1; echo two >&2; sleep 0.1; echo three; sleep 0.1; echo four >&2",
+        );
+        let mut cargo_output = CargoOutput::new();
+        cargo_output.warnings = false;
+        cargo_output.output = OutputKind::Discard;
+        let (status, output) = run_and_capture_output(&mut cmd, "sh", &cargo_output, None);
+        status.unwrap();
+
+        assert_eq!(
+            output.merged(),
+            [
+                (Stream::Stdout, "one".to_owned()),
+                (Stream::Stderr, "two".to_owned()),
+                (Stream::Stdout, "three".to_owned()),
+                (Stream::Stderr, "four".to_owned()),
+            ]
+        );
+        assert_eq!(output.text(), "one\ntwo\nthree\nfour\n");
+        assert_eq!(
+            output.lines_of(Stream::Stderr).collect::<Vec<_>>(),
+            ["two", "four"]
+        );
+    }
+
+    #[test]
+    fn debug_output_follows_the_env_vars() {
+        let set = |name: &'static str| {
+            move |var: &str| (var == name).then(|| std::ffi::OsString::from("1"))
+        };
+        assert!(debug_enabled(set("KOTLIN_RS_DEBUG")));
+        assert!(debug_enabled(set("CC_ENABLE_DEBUG_OUTPUT")));
+        assert!(!debug_enabled(set("RUST_LOG")));
+    }
+}
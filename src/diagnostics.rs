@@ -0,0 +1,276 @@
+//! Parsing of the diagnostics kotlinc prints to stderr
+
+use std::{
+    fmt,
+    io::{self, Write},
+    path::PathBuf,
+    sync::mpsc::{Receiver, Sender},
+    thread::JoinHandle,
+};
+
+use crate::command_helpers::Error;
+
+/// How severe a [`Diagnostic`] is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+/// A single message reported by kotlinc, optionally pointing at a location in a source file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: Option<PathBuf>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    /// The internal name of the diagnostic, such as `UNUSED_VARIABLE`, which kotlinc only
+    /// reports when [`crate::Build::render_diagnostic_names`] is enabled.
+    pub id: Option<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Parses a single line of kotlinc output, which is either `severity: message` or
+    /// `path:line:column: severity: message`. Returns `None` for any other line, such as the
+    /// source excerpts kotlinc prints below a located diagnostic.
+    pub(crate) fn parse(line: &str) -> Option<Diagnostic> {
+        let line = line.trim_end();
+
+        if let Some((severity, message)) = parse_severity(line) {
+            let (id, message) = parse_id(message);
+            return Some(Diagnostic {
+                severity,
+                file: None,
+                line: None,
+                column: None,
+                id,
+                message: message.to_owned(),
+            });
+        }
+
+        // Search from the left for the first `: <severity>: ` separator, as the message itself
+        // may contain colons, and make sure what precedes it is a `path:line:column` location.
+        let mut search_from = 0;
+        while let Some(offset) = line[search_from..].find(": ") {
+            let separator = search_from + offset;
+            if let Some((severity, message)) = parse_severity(&line[separator + 2..]) {
+                if let Some((file, line, column)) = parse_location(&line[..separator]) {
+                    let (id, message) = parse_id(message);
+                    return Some(Diagnostic {
+                        severity,
+                        file: Some(file),
+                        line: Some(line),
+                        column: Some(column),
+                        id,
+                        message: message.to_owned(),
+                    });
+                }
+            }
+            search_from = separator + 2;
+        }
+
+        None
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => f.write_str("error"),
+            Severity::Warning => f.write_str("warning"),
+            Severity::Info => f.write_str("info"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+            if let (Some(line), Some(column)) = (self.line, self.column) {
+                write!(f, "{}:{}:", line, column)?;
+            }
+            f.write_str(" ")?;
+        }
+        write!(f, "{}: ", self.severity)?;
+        if let Some(id) = &self.id {
+            write!(f, "[{}] ", id)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// The diagnostics of a running compile, yielded as kotlinc reports them. Returned by
+/// [`crate::Build::compile_diagnostics_iter`].
+///
+/// Iterating to the end waits for kotlinc to exit. Dropping the iterator early doesn't stop
+/// kotlinc, whose remaining output is then discarded.
+#[derive(Debug)]
+pub struct DiagnosticsIter {
+    receiver: Receiver<Diagnostic>,
+    compile: Option<JoinHandle<Result<(), Error>>>,
+    result: Option<Result<(), Error>>,
+}
+
+impl DiagnosticsIter {
+    pub(crate) fn new(
+        receiver: Receiver<Diagnostic>,
+        compile: JoinHandle<Result<(), Error>>,
+    ) -> Self {
+        Self {
+            receiver,
+            compile: Some(compile),
+            result: None,
+        }
+    }
+
+    /// Consumes the remaining diagnostics and returns whether kotlinc succeeded, which is an
+    /// error if it could not be run or exited unsuccessfully.
+    pub fn finish(mut self) -> Result<(), Error> {
+        self.by_ref().for_each(drop);
+        self.result.take().unwrap_or(Ok(()))
+    }
+}
+
+impl Iterator for DiagnosticsIter {
+    type Item = Diagnostic;
+
+    fn next(&mut self) -> Option<Diagnostic> {
+        match self.receiver.recv() {
+            Ok(diagnostic) => Some(diagnostic),
+            Err(_) => {
+                // The sender is dropped once kotlinc has exited and all of its output was read
+                if let Some(compile) = self.compile.take() {
+                    self.result = Some(compile.join().unwrap_or(Ok(())));
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Parses the lines written to it and sends each diagnostic among them through a channel.
+pub(crate) struct DiagnosticSender {
+    sender: Sender<Diagnostic>,
+    line: Vec<u8>,
+}
+
+impl DiagnosticSender {
+    pub(crate) fn new(sender: Sender<Diagnostic>) -> Self {
+        Self {
+            sender,
+            line: Vec::new(),
+        }
+    }
+}
+
+impl Write for DiagnosticSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+
+            if let Some(diagnostic) = Diagnostic::parse(&String::from_utf8_lossy(&self.line)) {
+                // The receiver may have been dropped, in which case the output is discarded
+                let _ = self.sender.send(diagnostic);
+            }
+            self.line.clear();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Parses every diagnostic in the given compiler output, skipping lines that aren't diagnostics.
+pub(crate) fn parse(output: &str) -> Vec<Diagnostic> {
+    output.lines().filter_map(Diagnostic::parse).collect()
+}
+
+fn parse_severity(s: &str) -> Option<(Severity, &str)> {
+    [
+        ("error: ", Severity::Error),
+        ("exception: ", Severity::Error),
+        ("warning: ", Severity::Warning),
+        ("info: ", Severity::Info),
+    ]
+    .into_iter()
+    .find_map(|(prefix, severity)| s.strip_prefix(prefix).map(|message| (severity, message)))
+}
+
+fn parse_location(location: &str) -> Option<(PathBuf, u32, u32)> {
+    let mut parts = location.rsplitn(3, ':');
+    let column = parts.next()?.parse().ok()?;
+    let line = parts.next()?.parse().ok()?;
+    let file = parts.next().filter(|file| !file.is_empty())?;
+    Some((file.into(), line, column))
+}
+
+/// Splits the `[NAME]` kotlinc puts in front of a message with
+/// `-Xrender-internal-diagnostic-names` off the message.
+fn parse_id(message: &str) -> (Option<String>, &str) {
+    let is_name_char = |c: char| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_';
+    message
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("] "))
+        .filter(|(id, _)| !id.is_empty() && id.chars().all(is_name_char))
+        .map_or((None, message), |(id, message)| {
+            (Some(id.to_owned()), message)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_diagnostic_names() {
+        let diagnostic = Diagnostic::parse(
+            "src/Main.kt:3:9: warning: [UNUSED_VARIABLE] variable 'x' is never used",
+        )
+        .unwrap();
+        assert_eq!(diagnostic.id.as_deref(), Some("UNUSED_VARIABLE"));
+        assert_eq!(diagnostic.message, "variable 'x' is never used");
+        assert_eq!(
+            diagnostic.to_string(),
+            "src/Main.kt:3:9: warning: [UNUSED_VARIABLE] variable 'x' is never used"
+        );
+
+        let diagnostic = Diagnostic::parse("error: [NO_SOURCES] no source files").unwrap();
+        assert_eq!(diagnostic.id.as_deref(), Some("NO_SOURCES"));
+        assert_eq!(diagnostic.file, None);
+    }
+
+    #[test]
+    fn brackets_in_messages_are_not_names() {
+        let diagnostic =
+            Diagnostic::parse("src/Main.kt:1:1: error: [x] is not a valid index").unwrap();
+        assert_eq!(diagnostic.id, None);
+        assert_eq!(diagnostic.message, "[x] is not a valid index");
+
+        let diagnostic = Diagnostic::parse("warning: variable 'x' is never used").unwrap();
+        assert_eq!(diagnostic.id, None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn severities_deserialize_from_their_names() {
+        use serde::{de::IntoDeserializer, Deserialize};
+
+        let deserializer: serde::de::value::StrDeserializer<serde::de::value::Error> =
+            "Warning".into_deserializer();
+        assert_eq!(Severity::deserialize(deserializer), Ok(Severity::Warning));
+
+        let deserializer: serde::de::value::StrDeserializer<serde::de::value::Error> =
+            "Fatal".into_deserializer();
+        assert!(Severity::deserialize(deserializer).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Miscellaneous helpers for working with the file system
+
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+/// Recursively collects every file below `dir`, sorted by path.
+pub(crate) fn walk_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    Ok(walk_files_to_depth(dir, usize::MAX)?.0)
+}
+
+/// Collects every file below `dir` that is at most `max_depth` directories deep, where the files
+/// directly in `dir` are at depth 0. Returns the files and the directories that were not
+/// descended into, both sorted by path.
+pub(crate) fn walk_files_to_depth(
+    dir: &Path,
+    max_depth: usize,
+) -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut files = vec![];
+    let mut skipped = vec![];
+    let mut pending = vec![(dir.to_path_buf(), 0)];
+
+    while let Some((dir, depth)) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                files.push(entry.path());
+            } else if depth < max_depth {
+                pending.push((entry.path(), depth + 1));
+            } else {
+                skipped.push(entry.path());
+            }
+        }
+    }
+
+    files.sort();
+    skipped.sort();
+    Ok((files, skipped))
+}
+
+/// Resolves the `.` and `..` components of an absolute path without accessing the file system.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// A directory that is removed together with its contents when dropped.
+#[derive(Debug)]
+pub(crate) struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    /// Creates an empty directory at `path`, removing anything a previous run left behind.
+    pub(crate) fn new(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = std::path::absolute(path.into())?;
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A temporary directory holding the output of [`crate::Build::compile_temp`], which is removed
+/// together with its contents when dropped.
+#[derive(Debug)]
+pub struct TempDir(pub(crate) ScratchDir);
+
+impl TempDir {
+    pub fn path(&self) -> &Path {
+        self.0.path()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walk_files_to_depth_stops_at_the_limit() {
+        let dir = crate::unique_scratch_dir("kotlin-test").unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("com/example/impl")).unwrap();
+        for file in [
+            "Top.class",
+            "com/A.class",
+            "com/example/B.class",
+            "com/example/impl/C.class",
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+
+        let (files, skipped) = walk_files_to_depth(root, 1).unwrap();
+        assert_eq!(files, [root.join("Top.class"), root.join("com/A.class")]);
+        assert_eq!(skipped, [root.join("com/example")]);
+
+        let (files, skipped) = walk_files_to_depth(root, 0).unwrap();
+        assert_eq!(files, [root.join("Top.class")]);
+        assert_eq!(skipped, [root.join("com")]);
+
+        assert_eq!(walk_files(root).unwrap().len(), 4);
+    }
+}
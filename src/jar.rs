@@ -0,0 +1,315 @@
+//! Helpers for inspecting and rewriting jars with the JDK `jar` tool
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::command_helpers::{run, run_and_capture_output, CargoOutput, Error, OutputKind, Stream};
+use crate::fs_helpers::{walk_files, ScratchDir};
+
+const MANIFEST: &str = "META-INF/MANIFEST.MF";
+
+/// The earliest timestamp a zip entry can carry, used for every entry of a reproducible jar.
+const REPRODUCIBLE_DATE: &str = "1980-01-01T00:00:02Z";
+
+/// How the entries of jars rewritten by this crate are compressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum JarCompression {
+    /// Entries are stored without compression, which is fastest to write and read.
+    Stored,
+    /// Entries are deflated at the default level of the JDK `jar` tool.
+    #[default]
+    Deflated,
+}
+
+/// Where the files of a classes directory are stored in an archive created by
+/// [`crate::Build::compile_and_archive`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ArchiveLayout {
+    /// Files keep their path relative to the classes directory, like in a jar.
+    Preserve,
+    /// Files are stored at the root of the archive under their file name. Fails if several
+    /// files have the same name.
+    Flatten,
+    /// Files keep their path relative to the classes directory, below the given directory of
+    /// the archive, such as `BOOT-INF/classes`.
+    Prefix(String),
+}
+
+impl ArchiveLayout {
+    /// Returns the name of the entry storing the file at `entry` of the classes directory.
+    pub(crate) fn entry_name(&self, entry: &str) -> String {
+        match self {
+            ArchiveLayout::Preserve => entry.to_owned(),
+            ArchiveLayout::Flatten => entry.rsplit('/').next().unwrap_or(entry).to_owned(),
+            ArchiveLayout::Prefix(prefix) => match prefix.trim_matches('/') {
+                "" => entry.to_owned(),
+                prefix => format!("{}/{}", prefix, entry),
+            },
+        }
+    }
+}
+
+pub(crate) struct JarTool<'a> {
+    program: PathBuf,
+    compression: JarCompression,
+    cargo_output: &'a CargoOutput,
+}
+
+impl<'a> JarTool<'a> {
+    /// Uses the `jar` tool of `java_home` if given, and the one on `PATH` otherwise.
+    pub(crate) fn new(java_home: Option<&Path>, cargo_output: &'a CargoOutput) -> Self {
+        let program = match java_home {
+            Some(java_home) => java_home.join("bin").join("jar"),
+            None => PathBuf::from("jar"),
+        };
+
+        Self {
+            program,
+            compression: JarCompression::default(),
+            cargo_output,
+        }
+    }
+
+    /// Sets the compression of jars created by this tool.
+    pub(crate) fn compression(mut self, compression: JarCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Extracts every entry of `jar` into `dir`.
+    pub(crate) fn extract(&self, jar: &Path, dir: &Path) -> Result<(), Error> {
+        let mut cmd = Command::new(&self.program);
+        cmd.arg("--extract")
+            .arg("--file")
+            .arg(std::path::absolute(jar)?)
+            .current_dir(dir);
+        run(&mut cmd, &self.program, self.cargo_output)
+    }
+
+    /// Returns the names of the entries of `jar`, in the order they are stored.
+    pub(crate) fn list(&self, jar: &Path) -> Result<Vec<String>, Error> {
+        let mut cmd = Command::new(&self.program);
+        cmd.arg("--list").arg("--file").arg(jar);
+
+        // The listing is only inspected, never forwarded
+        let mut cargo_output = self.cargo_output.clone();
+        cargo_output.warnings = false;
+        cargo_output.output = OutputKind::Discard;
+        cargo_output.fail_fast = false;
+        let (status, output) = run_and_capture_output(&mut cmd, &self.program, &cargo_output, None);
+        status?;
+
+        Ok(output
+            .lines_of(Stream::Stdout)
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_owned)
+            .collect())
+    }
+
+    /// Returns the contents of the manifest of `jar`, if it has one.
+    pub(crate) fn read_manifest(&self, jar: &Path) -> Result<Option<String>, Error> {
+        let scratch = ScratchDir::new(with_suffix(jar, ".manifest"))?;
+
+        let mut cmd = Command::new(&self.program);
+        cmd.arg("--extract")
+            .arg("--file")
+            .arg(std::path::absolute(jar)?)
+            .arg(MANIFEST)
+            .current_dir(scratch.path());
+        run(&mut cmd, &self.program, self.cargo_output)?;
+
+        let manifest = scratch.path().join(MANIFEST);
+        if manifest.exists() {
+            Ok(Some(fs::read_to_string(manifest)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Rewrites `jar` so that its bytes only depend on the contents of its entries: entries are
+    /// sorted by name and all carry the same fixed timestamp. Requires the `jar` tool of JDK 17
+    /// or newer.
+    pub(crate) fn make_reproducible(&self, jar: &Path) -> Result<(), Error> {
+        let scratch = ScratchDir::new(with_suffix(jar, ".contents"))?;
+        self.extract(jar, scratch.path())?;
+
+        let mut entries = entries_of(scratch.path())?;
+        entries.sort();
+
+        let rewritten = with_suffix(jar, ".rewritten");
+        let mut cmd = Command::new(&self.program);
+        cmd.arg("--create")
+            .arg("--file")
+            .arg(std::path::absolute(&rewritten)?)
+            .arg(format!("--date={}", REPRODUCIBLE_DATE));
+        self.create(cmd, scratch.path(), &entries)?;
+
+        fs::rename(&rewritten, jar)?;
+        Ok(())
+    }
+
+    /// Adds the entries of the jar at `previous` to `jar`. Entries present in both keep their
+    /// contents from `jar`, and their names are returned.
+    pub(crate) fn merge_previous(&self, previous: &Path, jar: &Path) -> Result<Vec<String>, Error> {
+        let merged = ScratchDir::new(with_suffix(jar, ".merged"))?;
+        self.extract(previous, merged.path())?;
+        let fresh = ScratchDir::new(with_suffix(jar, ".fresh"))?;
+        self.extract(jar, fresh.path())?;
+
+        let previous_entries = entries_of(merged.path())?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let mut duplicates = vec![];
+        for entry in entries_of(fresh.path())? {
+            let target = merged.path().join(&entry);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(fresh.path().join(&entry), target)?;
+
+            if entry != MANIFEST && previous_entries.contains(&entry) {
+                duplicates.push(entry);
+            }
+        }
+
+        let rewritten = with_suffix(jar, ".rewritten");
+        let mut cmd = Command::new(&self.program);
+        cmd.arg("--create")
+            .arg("--file")
+            .arg(std::path::absolute(&rewritten)?);
+        self.create(cmd, merged.path(), &entries_of(merged.path())?)?;
+
+        fs::rename(&rewritten, jar)?;
+        Ok(duplicates)
+    }
+
+    /// Packages the files below `classes` into a new jar at `jar`. The entries of the `runtime`
+    /// jars are bundled as well, except for their manifests; files from `classes` take
+    /// precedence over runtime entries with the same name.
+    pub(crate) fn package(
+        &self,
+        classes: &Path,
+        jar: &Path,
+        runtime: &[PathBuf],
+    ) -> Result<(), Error> {
+        let contents = ScratchDir::new(with_suffix(jar, ".contents"))?;
+        for runtime in runtime {
+            self.extract(runtime, contents.path())?;
+            let _ = fs::remove_file(contents.path().join(MANIFEST));
+        }
+
+        for entry in entries_of(classes)? {
+            let target = contents.path().join(&entry);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(classes.join(&entry), target)?;
+        }
+
+        let mut cmd = Command::new(&self.program);
+        cmd.arg("--create")
+            .arg("--file")
+            .arg(std::path::absolute(jar)?);
+        self.create(cmd, contents.path(), &entries_of(contents.path())?)
+    }
+
+    /// Creates a new jar at `jar` holding the given files, each stored under its entry name.
+    /// Later files replace earlier ones with the same entry name.
+    pub(crate) fn package_files(
+        &self,
+        files: &[(String, PathBuf)],
+        jar: &Path,
+    ) -> Result<(), Error> {
+        let contents = ScratchDir::new(with_suffix(jar, ".contents"))?;
+        for (entry, file) in files {
+            let target = contents.path().join(entry);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(file, target)?;
+        }
+
+        let mut cmd = Command::new(&self.program);
+        cmd.arg("--create")
+            .arg("--file")
+            .arg(std::path::absolute(jar)?);
+        self.create(cmd, contents.path(), &entries_of(contents.path())?)
+    }
+
+    /// Finishes a `jar --create` command by adding the manifest and the given entries of `dir`,
+    /// which are stored in the order given.
+    fn create(&self, mut cmd: Command, dir: &Path, entries: &[String]) -> Result<(), Error> {
+        let entries = entries
+            .iter()
+            .filter(|entry| *entry != MANIFEST)
+            .collect::<Vec<_>>();
+
+        if self.compression == JarCompression::Stored {
+            cmd.arg("--no-compress");
+        }
+
+        if dir.join(MANIFEST).exists() {
+            cmd.arg(format!("--manifest={}", MANIFEST));
+        } else {
+            cmd.arg("--no-manifest");
+        }
+
+        // Large modules easily exceed command line length limits, so pass the entries through an
+        // argument file, which the jar tool reads relative to its working directory.
+        let argfile = dir.with_extension("entries");
+        let contents = entries
+            .iter()
+            .map(|entry| format!("\"{}\"\n", entry.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect::<String>();
+        fs::write(&argfile, contents)?;
+
+        cmd.arg(format!("@{}", argfile.display())).current_dir(dir);
+        let result = run(&mut cmd, &self.program, self.cargo_output);
+        let _ = fs::remove_file(&argfile);
+        result
+    }
+}
+
+/// Returns the paths of every file below `dir` as jar entry names.
+pub(crate) fn entries_of(dir: &Path) -> Result<Vec<String>, Error> {
+    Ok(walk_files(dir)?
+        .iter()
+        .filter_map(|file| file.strip_prefix(dir).ok())
+        .map(|entry| {
+            entry
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .collect())
+}
+
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_layouts_name_entries() {
+        let entry = "com/example/MainKt.class";
+        assert_eq!(ArchiveLayout::Preserve.entry_name(entry), entry);
+        assert_eq!(ArchiveLayout::Flatten.entry_name(entry), "MainKt.class");
+        assert_eq!(
+            ArchiveLayout::Prefix("/BOOT-INF/classes/".into()).entry_name(entry),
+            "BOOT-INF/classes/com/example/MainKt.class"
+        );
+        assert_eq!(ArchiveLayout::Prefix("/".into()).entry_name(entry), entry);
+    }
+}
@@ -0,0 +1,161 @@
+//! Discovery of installed JDKs
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Finds the newest installed JDK whose feature version (`17` for `17.0.2`, `8` for
+/// `1.8.0_292`) is at least `min_version`.
+///
+/// Candidates are probed in this order, and on equal versions the first one found wins:
+///
+/// 1. `JAVA_HOME`
+/// 2. `/usr/lib/jvm/*` on Linux
+/// 3. `/Library/Java/JavaVirtualMachines/*/Contents/Home` on macOS
+/// 4. The `JavaHome` values registered under `HKLM\SOFTWARE\JavaSoft` on Windows, followed by
+///    `%ProgramFiles%\Java\*` and `%ProgramFiles%\Eclipse Adoptium\*`
+///
+/// Only directories with a `release` file declaring a `JAVA_VERSION` are considered.
+pub(crate) fn find_java_home(min_version: u32) -> Option<PathBuf> {
+    newest(candidates(), min_version)
+}
+
+/// Returns the candidate with the newest feature version of at least `min_version`, preferring
+/// earlier candidates on equal versions.
+fn newest(candidates: Vec<PathBuf>, min_version: u32) -> Option<PathBuf> {
+    let mut best: Option<(u32, PathBuf)> = None;
+
+    for home in candidates {
+        let Some(version) = feature_version(&home) else {
+            continue;
+        };
+        if version >= min_version && best.as_ref().is_none_or(|(best, _)| version > *best) {
+            best = Some((version, home));
+        }
+    }
+
+    best.map(|(_, home)| home)
+}
+
+fn candidates() -> Vec<PathBuf> {
+    let mut candidates = vec![];
+
+    if let Some(java_home) = env::var_os("JAVA_HOME").filter(|home| !home.is_empty()) {
+        candidates.push(PathBuf::from(java_home));
+    }
+
+    if cfg!(target_os = "linux") {
+        candidates.extend(subdirectories(Path::new("/usr/lib/jvm")));
+    }
+
+    if cfg!(target_os = "macos") {
+        candidates.extend(
+            subdirectories(Path::new("/Library/Java/JavaVirtualMachines"))
+                .into_iter()
+                .map(|jdk| jdk.join("Contents").join("Home")),
+        );
+    }
+
+    if cfg!(windows) {
+        candidates.extend(registered_java_homes());
+        if let Some(program_files) = env::var_os("ProgramFiles") {
+            let program_files = PathBuf::from(program_files);
+            candidates.extend(subdirectories(&program_files.join("Java")));
+            candidates.extend(subdirectories(&program_files.join("Eclipse Adoptium")));
+        }
+    }
+
+    candidates
+}
+
+fn subdirectories(dir: &Path) -> Vec<PathBuf> {
+    let mut subdirectories = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    subdirectories.sort();
+    subdirectories
+}
+
+/// Queries the Windows registry for the `JavaHome` of every registered JDK and JRE.
+fn registered_java_homes() -> Vec<PathBuf> {
+    let Ok(output) = Command::new("reg")
+        .args(["query", r"HKLM\SOFTWARE\JavaSoft", "/s", "/v", "JavaHome"])
+        .output()
+    else {
+        return vec![];
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once("REG_SZ"))
+        .map(|(_, home)| PathBuf::from(home.trim()))
+        .collect()
+}
+
+/// Reads the feature version of a JDK from the `JAVA_VERSION` in its `release` file.
+fn feature_version(home: &Path) -> Option<u32> {
+    let release = fs::read_to_string(home.join("release")).ok()?;
+    let version = release
+        .lines()
+        .find_map(|line| line.strip_prefix("JAVA_VERSION="))?
+        .trim_matches('"');
+
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    match parts.next()?.parse().ok()? {
+        1 => parts.next()?.parse().ok(),
+        feature => Some(feature),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a fake JDK in `dir` whose `release` file declares `version`.
+    fn fake_jdk(dir: &Path, name: &str, version: &str) -> PathBuf {
+        let home = dir.join(name);
+        fs::create_dir_all(home.join("bin")).unwrap();
+        fs::write(
+            home.join("release"),
+            format!("IMPLEMENTOR=\"Test\"\nJAVA_VERSION=\"{}\"\n", version),
+        )
+        .unwrap();
+        home
+    }
+
+    #[test]
+    fn feature_version_reads_the_release_file() {
+        let dir = crate::unique_scratch_dir("kotlin-test").unwrap();
+
+        let jdk8 = fake_jdk(dir.path(), "jdk8", "1.8.0_292");
+        let jdk17 = fake_jdk(dir.path(), "jdk17", "17.0.2");
+        let jdk21 = fake_jdk(dir.path(), "jdk21", "21");
+
+        assert_eq!(feature_version(&jdk8), Some(8));
+        assert_eq!(feature_version(&jdk17), Some(17));
+        assert_eq!(feature_version(&jdk21), Some(21));
+        assert_eq!(feature_version(dir.path()), None);
+    }
+
+    #[test]
+    fn newest_picks_the_newest_jdk_meeting_the_minimum() {
+        let dir = crate::unique_scratch_dir("kotlin-test").unwrap();
+        let jdk11 = fake_jdk(dir.path(), "jdk11", "11.0.20");
+        let jdk17 = fake_jdk(dir.path(), "jdk17", "17.0.2");
+        let other17 = fake_jdk(dir.path(), "other17", "17.0.9");
+        let broken = dir.path().join("broken");
+        fs::create_dir(&broken).unwrap();
+
+        let candidates = vec![broken, jdk11.clone(), jdk17.clone(), other17];
+        assert_eq!(newest(candidates.clone(), 8), Some(jdk17.clone()));
+        assert_eq!(newest(candidates.clone(), 17), Some(jdk17));
+        assert_eq!(newest(candidates.clone(), 21), None);
+        assert_eq!(newest(vec![jdk11.clone()], 11), Some(jdk11));
+    }
+}
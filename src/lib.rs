@@ -1,35 +1,297 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 
+mod checksum;
 mod command_helpers;
 use command_helpers::*;
+use fs_helpers::ScratchDir;
+use jar::JarTool;
+use version::CompilerVersion;
 
+mod diagnostics;
+mod fs_helpers;
+mod jar;
+mod java;
+mod module_graph;
+mod options;
+mod output_files;
+mod sources;
+mod toolchain;
+mod version;
+
+pub use checksum::ChecksumAlgorithm;
+pub use command_helpers::{Error, ErrorKind};
+pub use diagnostics::{Diagnostic, DiagnosticsIter, Severity};
+pub use fs_helpers::TempDir;
+pub use jar::{ArchiveLayout, JarCompression};
+pub use module_graph::ModuleGraph;
+pub use options::{
+    AssertionsMode, Backend, ClasspathOrder, DiagnosticFormat, ExperimentalFlag, InferenceFlag,
+    KotlinVersion, NullabilityMode, RuntimeVariant, StringConcatMode,
+};
+
+/// How deep [`Build::classpath_rerun_depth`] descends into classpath directories by default.
+const DEFAULT_CLASSPATH_RERUN_DEPTH: usize = 16;
+
+type AfterCompile = Box<dyn FnOnce(&Path) -> Result<(), Error> + Send>;
+type MissingCompiler = Box<dyn FnOnce() -> Result<PathBuf, Error> + Send>;
+type ClassTransform = Arc<dyn Fn(&str, Vec<u8>) -> Result<Vec<u8>, Error> + Send + Sync>;
+type ToolNotFoundHint = Arc<dyn Fn() -> String + Send + Sync>;
+
+/// The result of a successful [`Build::compile_checked`].
+#[derive(Clone, Debug)]
+pub struct CompileOutcome {
+    /// The jar or directory kotlinc wrote to.
+    pub artifact: PathBuf,
+    /// The warnings kotlinc reported while compiling.
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// A builder for compiling Kotlin sources with `kotlinc`.
+///
+/// With the `serde` feature, the configuration can be serialized and restored. The callbacks
+/// (such as [`Build::after_compile`]) and the compiler lookups cached by a build are not part of
+/// it, so a deserialized `Build` starts without them.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Build {
     files: Vec<PathBuf>,
+    java_files: Vec<PathBuf>,
+    source_roots: Vec<PathBuf>,
     classpath: Vec<PathBuf>,
+    module_path: Vec<PathBuf>,
+    friend_paths: Vec<PathBuf>,
+    fail_on_deprecation: Vec<String>,
+    raw_args_before_files: Vec<OsString>,
+    raw_args_after_files: Vec<OsString>,
+    experimental_flags: Vec<ExperimentalFlag>,
+    inference_flags: Vec<InferenceFlag>,
+    dump_phases: Vec<String>,
+    javac_args: Vec<String>,
+    metadata_vars: Vec<(String, String)>,
+    fragments: Vec<String>,
+    fragment_sources: Vec<(String, PathBuf)>,
+    common_sources: Vec<PathBuf>,
+    fragment_refines: Vec<(String, String)>,
     java_home: Option<PathBuf>,
+    build_file: Option<PathBuf>,
+    module_name: Option<String>,
+    source_encoding: Option<String>,
+    cache_key: Option<String>,
+    profile_output: Option<PathBuf>,
+    runtime_stdlib_jar: Option<PathBuf>,
+    toolchain: Option<PathBuf>,
+    backend: Backend,
+    classpath_order: ClasspathOrder,
+    runtime_variant: RuntimeVariant,
+    string_concat: Option<StringConcatMode>,
+    assertions: Option<AssertionsMode>,
+    jspecify_annotations: Option<NullabilityMode>,
+    language_version: Option<KotlinVersion>,
+    api_version: Option<KotlinVersion>,
+    checksum_algorithm: ChecksumAlgorithm,
+    jar_compression: JarCompression,
+    min_java_version: u32,
+    max_warnings: Option<usize>,
+    backend_threads: Option<usize>,
+    classpath_rerun_depth: usize,
+    command_wrapper: Option<(OsString, Vec<OsString>)>,
+    inherit_java_home: bool,
     include_runtime: bool,
+    disable_friend_modules: bool,
+    emit_checksum: bool,
+    extra_checks: bool,
+    fallback_to_k1: bool,
     no_jdk: bool,
     no_reflect: bool,
     no_stdlib: bool,
+    profile: bool,
+    report_output_files: bool,
+    reproducible: bool,
+    validate_packages: bool,
+    skip_prerelease_check: bool,
+    auto_java_home: bool,
+    append_to_jar: bool,
+    render_diagnostic_names: bool,
+    allow_empty_sources: bool,
+    optimize: bool,
+    extended_checkers: bool,
+    emit_sources_jar: bool,
+    include_java_sources: bool,
+    warn_classpath_overlap: bool,
+    strict_out_dir: bool,
+    expect_actual_classes: bool,
+    disable_scripting_plugin: bool,
+    use_javac: bool,
+    ir_inliner: bool,
+    emit_jvm_type_annotations: bool,
+    canonical_command: bool,
     warnings_into_errors: bool,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    after_compile: Mutex<Option<AfterCompile>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    on_tool_not_found: Option<ToolNotFoundHint>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    transform_class: Option<ClassTransform>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    on_missing_compiler: Mutex<Option<MissingCompiler>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    compiler: OnceLock<Result<PathBuf, Error>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    compiler_version: OnceLock<Result<CompilerVersion, Error>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    discovered_java_home: OnceLock<Option<PathBuf>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    probed_flags: Mutex<HashMap<String, bool>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    last_command: Mutex<Option<String>>,
     cargo_output: CargoOutput,
 }
 
+impl Default for Build {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Build {
     pub fn new() -> Self {
         Self {
             files: vec![],
+            java_files: vec![],
+            source_roots: vec![],
             classpath: vec![],
+            module_path: vec![],
+            friend_paths: vec![],
+            fail_on_deprecation: vec![],
+            raw_args_before_files: vec![],
+            raw_args_after_files: vec![],
+            experimental_flags: vec![],
+            inference_flags: vec![],
+            dump_phases: vec![],
+            javac_args: vec![],
+            metadata_vars: vec![],
+            fragments: vec![],
+            fragment_sources: vec![],
+            common_sources: vec![],
+            fragment_refines: vec![],
             java_home: None,
+            build_file: None,
+            module_name: None,
+            source_encoding: None,
+            cache_key: None,
+            profile_output: None,
+            runtime_stdlib_jar: None,
+            toolchain: None,
+            backend: Backend::Default,
+            classpath_order: ClasspathOrder::AsAdded,
+            runtime_variant: RuntimeVariant::Full,
+            string_concat: None,
+            assertions: None,
+            jspecify_annotations: None,
+            language_version: None,
+            api_version: None,
+            checksum_algorithm: ChecksumAlgorithm::Sha256,
+            jar_compression: JarCompression::Deflated,
+            min_java_version: 0,
+            max_warnings: None,
+            backend_threads: None,
+            classpath_rerun_depth: DEFAULT_CLASSPATH_RERUN_DEPTH,
+            command_wrapper: None,
+            inherit_java_home: true,
             include_runtime: false,
+            disable_friend_modules: false,
+            emit_checksum: false,
+            extra_checks: false,
+            fallback_to_k1: false,
             no_jdk: false,
             no_reflect: false,
             no_stdlib: false,
+            profile: false,
+            report_output_files: false,
+            reproducible: false,
+            validate_packages: false,
+            skip_prerelease_check: false,
+            auto_java_home: false,
+            append_to_jar: false,
+            render_diagnostic_names: false,
+            allow_empty_sources: false,
+            optimize: true,
+            extended_checkers: false,
+            emit_sources_jar: false,
+            include_java_sources: false,
+            warn_classpath_overlap: true,
+            strict_out_dir: false,
+            expect_actual_classes: false,
+            disable_scripting_plugin: false,
+            use_javac: false,
+            ir_inliner: false,
+            emit_jvm_type_annotations: false,
+            canonical_command: false,
             warnings_into_errors: false,
+            after_compile: Mutex::new(None),
+            on_tool_not_found: None,
+            transform_class: None,
+            on_missing_compiler: Mutex::new(None),
+            compiler: OnceLock::new(),
+            compiler_version: OnceLock::new(),
+            discovered_java_home: OnceLock::new(),
+            probed_flags: Mutex::new(HashMap::new()),
+            last_command: Mutex::new(None),
             cargo_output: CargoOutput::new(),
         }
     }
+
+    /// Creates a builder for test sources compiled against `main_output`, the output of the
+    /// module under test. It is added to both the classpath and the friend paths so the tests
+    /// can access `internal` declarations.
+    pub fn test_build(main_output: &Path) -> Self {
+        let mut build = Self::new();
+        build.classpath(main_output).friend_path(main_output);
+        build
+    }
+
+    /// Creates a builder whose classpath holds the jars shared by the build scripts of this
+    /// package's dependencies, as read from every `DEP_<LINKS>_<KEY>` environment variable.
+    /// Dependencies publish them with [`Build::emit_metadata_var`] and a `links` key, so with
+    /// `key` set to `"JAR"`, a dependency with `links = "core"` that emitted `cargo:jar=...` is
+    /// picked up from `DEP_CORE_JAR`. A variable may list several paths, separated like `PATH`.
+    ///
+    /// The variables are read in order of their names.
+    pub fn from_cargo_dependencies(key: &str) -> Self {
+        Self::from_dependency_vars(key, std::env::vars_os())
+    }
+
+    /// Like [`Build::from_cargo_dependencies`], but reads the variables from `vars`.
+    fn from_dependency_vars(
+        key: &str,
+        vars: impl IntoIterator<Item = (OsString, OsString)>,
+    ) -> Self {
+        let suffix = format!("_{}", key.to_ascii_uppercase().replace('-', "_"));
+        let mut vars = vars
+            .into_iter()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value)))
+            .filter(|(name, _)| name.starts_with("DEP_") && name.ends_with(&suffix))
+            .collect::<Vec<_>>();
+        vars.sort();
+
+        let mut build = Self::new();
+        for (name, value) in vars {
+            build
+                .cargo_output
+                .print_metadata(&format_args!("cargo:rerun-if-env-changed={}", name));
+            build.classpaths(
+                std::env::split_paths(&value).filter(|path| !path.as_os_str().is_empty()),
+            );
+        }
+        build
+    }
+
     pub fn warnings_into_errors(&mut self, warnings_into_errors: bool) -> &mut Self {
         self.warnings_into_errors = warnings_into_errors;
         self
@@ -50,21 +312,494 @@ impl Build {
         self
     }
 
+    /// Passes `-Xreport-output-files`, making kotlinc report which files it generated from
+    /// each source. [`Build::compile_with_outputs`] always enables this. The report is kept out
+    /// of the warnings forwarded to cargo, but written to the file of [`Build::log_output_to`].
+    pub fn report_output_files(&mut self, report_output_files: bool) -> &mut Self {
+        self.report_output_files = report_output_files;
+        self
+    }
+
+    /// Passes `-Xreport-perf`, making kotlinc report how long each compiler phase took. If
+    /// `output` is given, the measurements are additionally written to that file through
+    /// `-Xdump-perf`.
+    ///
+    /// The report format differs between compiler versions, so it is forwarded as-is rather than
+    /// parsed.
+    pub fn profile(&mut self, output: Option<PathBuf>) -> &mut Self {
+        self.profile = true;
+        self.profile_output = output;
+        self
+    }
+
+    /// Rewrites jar outputs so that compiling the same inputs yields byte-identical jars: entries
+    /// are sorted by name and their timestamps are fixed. This requires the `jar` tool of JDK 17
+    /// or newer, found in [`Build::java_home`] or on `PATH`.
+    ///
+    /// Output directories are left as-is, and kotlinc offers no flag of its own for this.
+    /// Differences caused by other inputs, such as a different compiler or JDK version, or
+    /// differing source paths embedded in debug information, are not addressed.
+    pub fn reproducible(&mut self, reproducible: bool) -> &mut Self {
+        self.reproducible = reproducible;
+        self
+    }
+
+    /// After a successful compile to a jar, writes its digest to a `<output>.sha256` sidecar
+    /// file in the format of `sha256sum` and prints it as `cargo:sha256=<digest>` metadata.
+    pub fn emit_checksum(&mut self, emit_checksum: bool) -> &mut Self {
+        self.emit_checksum = emit_checksum;
+        self
+    }
+
+    /// Selects the hash algorithm used by [`Build::emit_checksum`].
+    pub fn checksum_algorithm(&mut self, checksum_algorithm: ChecksumAlgorithm) -> &mut Self {
+        self.checksum_algorithm = checksum_algorithm;
+        self
+    }
+
+    /// Selects the compression of jars this crate rewrites, as done by [`Build::reproducible`],
+    /// [`Build::append_to_jar`] and [`Build::jar_from_classes`]. Jars written by kotlinc itself
+    /// are not affected.
+    pub fn jar_compression(&mut self, jar_compression: JarCompression) -> &mut Self {
+        self.jar_compression = jar_compression;
+        self
+    }
+
     pub fn include_runtime(&mut self, include_runtime: bool) -> &mut Self {
         self.include_runtime = include_runtime;
         self
     }
 
+    /// Passes `-Xfriend-modules-disabled`, denying access to `internal` declarations of any
+    /// other module. Cannot be combined with [`Build::friend_path`].
+    pub fn disable_friend_modules(&mut self, disable_friend_modules: bool) -> &mut Self {
+        self.disable_friend_modules = disable_friend_modules;
+        self
+    }
+
+    /// Enables additional compiler checks that are off by default, using
+    /// `-Xuse-fir-extended-checkers` for Kotlin 2.0 and newer and `-Xextended-compiler-checks`
+    /// for older compilers. Compilers without either flag only emit a warning. Cannot be combined
+    /// with [`Backend::K1`], as the checkers of Kotlin 2.0 require the K2 frontend.
+    pub fn extra_checks(&mut self, extra_checks: bool) -> &mut Self {
+        self.extra_checks = extra_checks;
+        self
+    }
+
+    /// Selects the compiler frontend, using the flags the detected compiler version expects.
+    pub fn backend(&mut self, backend: Backend) -> &mut Self {
+        self.backend = backend;
+        self
+    }
+
+    /// When [`Backend::K2`] is selected and kotlinc exits unsuccessfully, retries once with
+    /// [`Backend::K1`]. Stopping at the first error through [`Build::fail_fast`] and failing
+    /// [`Build::max_warnings`] or [`Build::fail_on_deprecation`] don't trigger a retry.
+    pub fn fallback_to_k1(&mut self, fallback_to_k1: bool) -> &mut Self {
+        self.fallback_to_k1 = fallback_to_k1;
+        self
+    }
+
+    /// Passes `-Xskip-prerelease-check`, allowing the use of libraries compiled by pre-release
+    /// versions of Kotlin.
+    ///
+    /// **Warning:** pre-release binaries carry no ABI stability guarantees. Code compiled against
+    /// them may fail at runtime with linkage errors once the library or compiler changes.
+    pub fn skip_prerelease_check(&mut self, skip_prerelease_check: bool) -> &mut Self {
+        self.skip_prerelease_check = skip_prerelease_check;
+        self
+    }
+
+    /// When no explicit [`Build::java_home`] is set, probes common install locations for JDKs and
+    /// passes the newest one meeting [`Build::min_java_version`] as `-java-home`. This takes
+    /// precedence over [`Build::inherit_java_home`], which is used as a fallback if no JDK is found.
+    ///
+    /// Locations are probed in this order, with the first one found winning between equal versions:
+    /// `JAVA_HOME`, `/usr/lib/jvm/*` on Linux, `/Library/Java/JavaVirtualMachines/*/Contents/Home`
+    /// on macOS, and on Windows the `JavaHome` values registered under `HKLM\SOFTWARE\JavaSoft`
+    /// followed by `%ProgramFiles%\Java\*` and `%ProgramFiles%\Eclipse Adoptium\*`. Only
+    /// directories with a `release` file declaring their version are considered.
+    pub fn auto_java_home(&mut self, auto_java_home: bool) -> &mut Self {
+        self.auto_java_home = auto_java_home;
+        self
+    }
+
+    /// The minimum feature version, such as `17`, of a JDK selected by [`Build::auto_java_home`].
+    pub fn min_java_version(&mut self, min_java_version: u32) -> &mut Self {
+        self.min_java_version = min_java_version;
+        self
+    }
+
+    /// Fails the compile once kotlinc reports more than `max_warnings` warnings, so the number of
+    /// warnings in a codebase can be ratcheted down over time.
+    pub fn max_warnings(&mut self, max_warnings: usize) -> &mut Self {
+        self.max_warnings = Some(max_warnings);
+        self
+    }
+
+    /// When the output jar already exists, adds the newly compiled classes to it instead of
+    /// replacing it. Entries present in both keep the newly compiled contents, and a warning lists
+    /// them. This requires the JDK `jar` tool, found in [`Build::java_home`] or on `PATH`.
+    pub fn append_to_jar(&mut self, append_to_jar: bool) -> &mut Self {
+        self.append_to_jar = append_to_jar;
+        self
+    }
+
+    /// Prefixes every diagnostic with its internal name, such as `[UNUSED_VARIABLE]`, which is then
+    /// available as [`Diagnostic::id`] from [`Build::compile_with_diagnostics`].
+    pub fn render_diagnostic_names(&mut self, render_diagnostic_names: bool) -> &mut Self {
+        self.render_diagnostic_names = render_diagnostic_names;
+        self
+    }
+
+    /// Passes `-Xallow-no-source-files`, so compiling without any source files produces an empty
+    /// output instead of failing. Without it kotlinc rejects such a compile, which is the only check
+    /// for missing sources as this crate doesn't validate them itself.
+    pub fn allow_empty_sources(&mut self, allow_empty_sources: bool) -> &mut Self {
+        self.allow_empty_sources = allow_empty_sources;
+        self
+    }
+
+    /// Disables bytecode optimizations when set to `false`, passing `-Xno-optimize`, so that the
+    /// compiled code follows the sources closely when stepping through it in a debugger. This makes
+    /// the code slower and should only be used for debugging. Enabled by default.
+    pub fn optimize(&mut self, optimize: bool) -> &mut Self {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Passes `-Xuse-fir-extended-checkers`, enabling the additional checkers of the K2 frontend for
+    /// stricter analysis. Cannot be combined with [`Backend::K1`].
+    pub fn extended_checkers(&mut self, extended_checkers: bool) -> &mut Self {
+        self.extended_checkers = extended_checkers;
+        self
+    }
+
+    /// Packages the source files into a sources jar next to the output after every successful
+    /// compile, as published alongside libraries. The jar is named after the output with a
+    /// `-sources.jar` suffix, and holds the files below each [`Build::source_root`] at their
+    /// path relative to the root and every [`Build::file`] in the directory of its package.
+    pub fn emit_sources_jar(&mut self, emit_sources_jar: bool) -> &mut Self {
+        self.emit_sources_jar = emit_sources_jar;
+        self
+    }
+
+    /// Warns when a classpath jar looks like a previous build of the module being compiled, which
+    /// leads to duplicate classes. A jar is considered a match if it is named after
+    /// [`Build::module_name`], optionally followed by a `-` and a version. Enabled by default.
+    pub fn warn_classpath_overlap(&mut self, warn_classpath_overlap: bool) -> &mut Self {
+        self.warn_classpath_overlap = warn_classpath_overlap;
+        self
+    }
+
+    /// When running under Cargo, as detected through the `CARGO` environment variable, fails
+    /// compiles whose output is outside of `OUT_DIR`, or that run without `OUT_DIR` being set.
+    /// Outputs elsewhere escape Cargo's target directory and aren't cleaned or cached by it.
+    pub fn strict_out_dir(&mut self, strict_out_dir: bool) -> &mut Self {
+        self.strict_out_dir = strict_out_dir;
+        self
+    }
+
+    /// Allows `expect` and `actual` classes in multiplatform sources, passed as
+    /// `-Xexpect-actual-classes`, which silences kotlinc's warning that they are in beta. The
+    /// flag is only passed to compilers that know it, Kotlin 1.9.20 and newer, and ignored with a
+    /// warning otherwise.
+    pub fn expect_actual_classes(&mut self, expect_actual_classes: bool) -> &mut Self {
+        self.expect_actual_classes = expect_actual_classes;
+        self
+    }
+
+    /// Disables kotlinc's default scripting plugin, passed as `-Xdisable-default-scripting-plugin`,
+    /// which saves a little startup time when no scripts are compiled. Scripts (`.kts` files) need
+    /// the plugin, so they cannot be compiled with this option.
+    pub fn disable_scripting_plugin(&mut self, disable_scripting_plugin: bool) -> &mut Self {
+        self.disable_scripting_plugin = disable_scripting_plugin;
+        self
+    }
+
+    /// Makes kotlinc compile the Java sources too, by running javac in the same process after the
+    /// Kotlin sources. Passes `-Xuse-javac`, which has javac analyze the Java sources, together
+    /// with `-Xcompile-java`, which has it produce their class files. Requires Java sources from
+    /// [`Build::java_file`] or [`Build::include_java_sources`].
+    pub fn use_javac(&mut self, use_javac: bool) -> &mut Self {
+        self.use_javac = use_javac;
+        self
+    }
+
+    /// Passes an argument on to javac when [`Build::use_javac`] is enabled, such as `-parameters`.
+    /// The arguments are passed as `-Xjavac-arguments`, which separates them by commas, so an
+    /// argument cannot contain one.
+    pub fn javac_arg<S: AsRef<str>>(&mut self, arg: S) -> &mut Self {
+        self.javac_args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Passes `-Xir-inliner`, inlining functions on the IR of the JVM backend instead of on the
+    /// generated bytecode. This changes the bytecode produced for inline functions and lambdas, and
+    /// can make compiling slower or faster depending on how heavily the module uses inlining.
+    /// Requires kotlinc 2.0 and cannot be combined with [`Backend::K1`].
+    pub fn ir_inliner(&mut self, ir_inliner: bool) -> &mut Self {
+        self.ir_inliner = ir_inliner;
+        self
+    }
+
+    /// Passes `-Xemit-jvm-type-annotations`, storing type-use annotations such as those on generic
+    /// type arguments in the bytecode, so Java frameworks that read them through reflection, like
+    /// validation or serialization libraries, see them on Kotlin declarations too. Requires kotlinc
+    /// 1.4.
+    pub fn emit_jvm_type_annotations(&mut self, emit_jvm_type_annotations: bool) -> &mut Self {
+        self.emit_jvm_type_annotations = emit_jvm_type_annotations;
+        self
+    }
+
+    /// Sorts the values of flags whose order kotlinc ignores, such as those added through
+    /// [`Build::experimental_flag`], [`Build::friend_path`] or [`Build::dump_phase`], so builders
+    /// configured in a different order produce the same command line, which keeps caches keyed on
+    /// the command stable. Sources, the classpath and raw arguments keep the order they were added
+    /// in, as it can matter to kotlinc.
+    pub fn canonical_command(&mut self, canonical_command: bool) -> &mut Self {
+        self.canonical_command = canonical_command;
+        self
+    }
+
     pub fn java_home<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
         self.java_home = Some(p.as_ref().into());
         self
     }
 
+    /// Whether `JAVA_HOME` is passed to kotlinc as `-java-home` when no explicit
+    /// [`Build::java_home`] is set. Enabled by default.
+    pub fn inherit_java_home(&mut self, inherit_java_home: bool) -> &mut Self {
+        self.inherit_java_home = inherit_java_home;
+        self
+    }
+
+    /// Runs kotlinc through a wrapper, so the invocation becomes
+    /// `<program> <args> kotlinc-jvm ...`. Errors about a missing tool then refer to `program`.
+    pub fn command_wrapper<S: AsRef<OsStr>>(
+        &mut self,
+        program: S,
+        args: Vec<OsString>,
+    ) -> &mut Self {
+        self.command_wrapper = Some((program.as_ref().to_owned(), args));
+        self
+    }
+
+    /// Adds arguments that are passed to kotlinc as-is, after every flag set through this builder
+    /// and directly before the source files.
+    pub fn raw_args_before_files(&mut self, args: Vec<OsString>) -> &mut Self {
+        self.raw_args_before_files.extend(args);
+        self
+    }
+
+    /// Adds arguments that are passed to kotlinc as-is, directly after the source files and
+    /// before the output directory.
+    pub fn raw_args_after_files(&mut self, args: Vec<OsString>) -> &mut Self {
+        self.raw_args_after_files.extend(args);
+        self
+    }
+
+    /// Sets the Kotlin language version the sources are compiled with, passed as
+    /// `-language-version`. [`KotlinVersion::LatestStable`] follows the installed compiler, so
+    /// the version doesn't have to be bumped by hand. Cannot be combined with a [`Backend`] that
+    /// is selected through `-language-version` on the installed compiler.
+    pub fn language_version(&mut self, version: KotlinVersion) -> &mut Self {
+        self.language_version = Some(version);
+        self
+    }
+
+    /// Sets the version of the Kotlin standard library API the sources may use, passed as
+    /// `-api-version`.
+    pub fn api_version(&mut self, version: KotlinVersion) -> &mut Self {
+        self.api_version = Some(version);
+        self
+    }
+
+    /// Selects how `assert()` calls are compiled, passed as `-Xassertions`. Without it, kotlinc
+    /// checks assertions based on the `-ea` switch of the JVM running the code, but evaluates
+    /// the asserted expression even when they are disabled.
+    pub fn assertions(&mut self, mode: AssertionsMode) -> &mut Self {
+        self.assertions = Some(mode);
+        self
+    }
+
+    /// Selects how the nullability of Java declarations annotated with JSpecify annotations is
+    /// enforced, passed as `-Xjspecify-annotations`.
+    pub fn jspecify_annotations(&mut self, mode: NullabilityMode) -> &mut Self {
+        self.jspecify_annotations = Some(mode);
+        self
+    }
+
+    /// Sets how many threads the JVM backend uses to generate code, passed as
+    /// `-Xbackend-threads`, which speeds up compiling large modules. Build scripts can follow
+    /// Cargo's parallelism by passing the value of its `NUM_JOBS` environment variable.
+    ///
+    /// `n` has to be at least 1, and the flag requires Kotlin 1.6.20 or newer.
+    pub fn backend_threads(&mut self, n: usize) -> &mut Self {
+        self.backend_threads = Some(n);
+        self
+    }
+
+    /// Selects how string concatenations are compiled, passed as `-Xstring-concat`. Compiling
+    /// fails if the detected compiler is older than Kotlin 1.4.20, which introduced the flag.
+    pub fn string_concat(&mut self, mode: StringConcatMode) -> &mut Self {
+        self.string_concat = Some(mode);
+        self
+    }
+
+    /// Enables one of the narrow experimental flags covered by [`ExperimentalFlag`]. Compiling
+    /// fails if the detected compiler is too old to know the flag.
+    pub fn experimental_flag(&mut self, flag: ExperimentalFlag) -> &mut Self {
+        if !self.experimental_flags.contains(&flag) {
+            self.experimental_flags.push(flag);
+        }
+        self
+    }
+
+    /// Enables one of the type-inference toggles of kotlinc. Compiling fails if the detected
+    /// compiler is too old to know the flag. Enabling a flag twice passes it once.
+    pub fn inference_flag(&mut self, flag: InferenceFlag) -> &mut Self {
+        if !self.inference_flags.contains(&flag) {
+            self.inference_flags.push(flag);
+        }
+        self
+    }
+
+    /// Makes [`Build::compile`] and [`Build::compile_with_diagnostics`] skip kotlinc when the
+    /// output exists and was produced with the same `key`, such as the version of the generator
+    /// that wrote the sources. The key is recorded in a `<output>.cache-key` file after every
+    /// successful compile. Changes to the sources or other options are not detected, so the key
+    /// has to cover everything that should trigger a recompile.
+    pub fn cache_key<S: AsRef<str>>(&mut self, key: S) -> &mut Self {
+        self.cache_key = Some(key.as_ref().to_owned());
+        self
+    }
+
+    /// Dumps the IR after the given lowering phase of the JVM IR backend, such as
+    /// `"JvmInlineClassLowering"`, passed as `-Xphases-to-dump`. Can be called repeatedly to dump
+    /// several phases.
+    ///
+    /// This is an advanced feature for diagnosing compiler bugs: the phase names are internal to
+    /// kotlinc and change between versions, and the dumps are printed with the compiler's output.
+    pub fn dump_phase<S: AsRef<str>>(&mut self, phase: S) -> &mut Self {
+        self.dump_phases.push(phase.as_ref().to_owned());
+        self
+    }
+
+    /// Compiles the modules described by a kotlinc build file through `-Xbuild-file`. Sources,
+    /// classpath and output directory are then taken from the build file, so neither
+    /// [`Build::file`] nor [`Build::classpath`] may be used, and the `output` passed to
+    /// [`Build::compile`] is not forwarded to kotlinc.
+    pub fn build_file<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
+        self.build_file = Some(p.as_ref().into());
+        self
+    }
+
+    /// Sets the name of the module being compiled, passed as `-module-name`. kotlinc names the
+    /// module's `.kotlin_module` file after it, and uses it to mangle internal declarations.
+    pub fn module_name<S: AsRef<str>>(&mut self, name: S) -> &mut Self {
+        self.module_name = Some(name.as_ref().to_owned());
+        self
+    }
+
+    /// Sets the charset the source files are read with, such as `windows-1252`, instead of
+    /// UTF-8. kotlinc has no option for it, so it is passed to the compiler's JVM as
+    /// `-J-Dfile.encoding`. Compiling fails if the name is not a charset known to the JVM.
+    pub fn source_encoding<S: AsRef<str>>(&mut self, charset: S) -> &mut Self {
+        self.source_encoding = Some(charset.as_ref().to_owned());
+        self
+    }
+
     pub fn file<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
         self.files.push(p.as_ref().into());
         self
     }
 
+    /// Adds every `.kt` file tracked by the git repository at `repo_root`, as listed by
+    /// `git ls-files`. Unlike [`Build::source_root`], this skips generated and gitignored files
+    /// that happen to be in the source tree. Files that are tracked but deleted from the
+    /// working tree are still added, and fail the compile.
+    ///
+    /// Fails if `git` can't be run or `repo_root` is not inside a git repository.
+    pub fn files_from_git<P: AsRef<Path>>(&mut self, repo_root: P) -> Result<&mut Self, Error> {
+        let files = sources::git_tracked_files(repo_root.as_ref(), &self.cargo_output)?;
+        self.files.extend(files);
+        Ok(self)
+    }
+
+    /// Passes a Java source file to kotlinc alongside the Kotlin sources, so Kotlin code can use
+    /// the declarations in it. kotlinc only reads Java sources to resolve references and does
+    /// not compile them unless [`Build::use_javac`] is enabled, so their classes otherwise have
+    /// to be produced by javac.
+    pub fn java_file<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
+        self.java_files.push(p.as_ref().into());
+        self
+    }
+
+    /// Declares a fragment of a multiplatform module, such as `common` or `jvm`, for the
+    /// fragment model of `-Xfragments`. Declaring fragments enables `-Xmulti-platform`.
+    pub fn fragment(&mut self, name: &str) -> &mut Self {
+        if !self.fragments.iter().any(|fragment| fragment == name) {
+            self.fragments.push(name.to_owned());
+        }
+        self
+    }
+
+    /// Adds a source file belonging to a fragment declared with [`Build::fragment`]. The file is
+    /// compiled like one added through [`Build::file`], and its fragment is passed to kotlinc
+    /// through `-Xfragment-sources`.
+    pub fn fragment_source(&mut self, fragment: &str, path: &Path) -> &mut Self {
+        self.fragment_sources
+            .push((fragment.to_owned(), path.to_path_buf()));
+        self
+    }
+
+    /// Declares that fragment `from` refines fragment `to`, such as `jvm` refining `common`, so
+    /// `from` can provide `actual` declarations for the `expect` declarations of `to`. Passed to
+    /// kotlinc through `-Xfragment-refines`.
+    pub fn fragment_refines(&mut self, from: &str, to: &str) -> &mut Self {
+        self.fragment_refines.push((from.to_owned(), to.to_owned()));
+        self
+    }
+
+    /// Compiles every `.kt` file found below `dir` like [`Build::file`], and marks them as the
+    /// common sources of a multiplatform module, which may declare `expect` declarations, through
+    /// `-Xcommon-sources`. Enables `-Xmulti-platform`.
+    ///
+    /// Fails if `dir` can't be read.
+    pub fn common_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<&mut Self, Error> {
+        let dir = dir.as_ref();
+        let files = sources::source_files(dir, false).map_err(|e| {
+            e.context(format_args!(
+                "Failed to read common directory {}",
+                dir.display()
+            ))
+        })?;
+        self.files.extend(files.iter().cloned());
+        self.common_sources.extend(files);
+        Ok(self)
+    }
+
+    /// Compiles every `.kt` file found below `dir`, which is the root of a package hierarchy.
+    pub fn source_root<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.source_roots.push(dir.as_ref().into());
+        self
+    }
+
+    /// Makes [`Build::source_root`] collect `.java` files too, which are passed to kotlinc like
+    /// the ones added through [`Build::java_file`].
+    pub fn include_java_sources(&mut self, include_java_sources: bool) -> &mut Self {
+        self.include_java_sources = include_java_sources;
+        self
+    }
+
+    /// Checks that every file found through [`Build::source_root`] declares the package that
+    /// matches its directory relative to the root, failing the compile otherwise.
+    pub fn validate_packages(&mut self, validate_packages: bool) -> &mut Self {
+        self.validate_packages = validate_packages;
+        self
+    }
+
     pub fn classpath<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
         self.classpath.push(p.as_ref().into());
         self
@@ -81,50 +816,4916 @@ impl Build {
         self
     }
 
-    pub fn compile(&self, output: &str) -> Result<(), Error> {
-        let mut cmd = Command::new("kotlinc-jvm");
+    /// Adds a modular jar or a directory of modules to the Java module path, which kotlinc reads
+    /// through `-Xmodule-path` instead of the classpath. Compiling fails if the entry doesn't
+    /// exist.
+    pub fn module_path<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
+        self.module_path.push(p.as_ref().into());
+        self
+    }
 
-        if !self.classpath.is_empty() {
-            let classpath = self
-                .classpath
-                .iter()
-                .map(|p| p.display().to_string())
-                .collect::<Vec<String>>()
-                .join(":");
+    /// Compiles against the Android platform jar at `p` instead of the JDK: the jar is added to
+    /// the classpath and [`Build::no_jdk`] is enabled. Fails if the jar doesn't exist.
+    ///
+    /// This only produces JVM class files compiled against the Android APIs. Dexing them and
+    /// packaging an APK is left to the Android build tools.
+    pub fn android_jar<P: AsRef<Path>>(&mut self, p: P) -> Result<&mut Self, Error> {
+        let p = p.as_ref();
+        if !p.is_file() {
+            return Err(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!("Android platform jar {} does not exist", p.display()),
+            ));
+        }
 
-            cmd.arg("-cp").arg(classpath);
+        Ok(self.classpath(p).no_jdk(true))
+    }
+
+    /// Controls whether the Kotlin runtime jars on the classpath come before or after the other
+    /// entries, which decides the winner when a class is present in several entries. Defaults
+    /// to [`ClasspathOrder::AsAdded`].
+    pub fn classpath_order(&mut self, classpath_order: ClasspathOrder) -> &mut Self {
+        self.classpath_order = classpath_order;
+        self
+    }
+
+    /// Limits how many directory levels below a classpath directory are walked to emit
+    /// `cargo:rerun-if-changed` for each class file in it, so huge trees don't flood cargo with
+    /// metadata. Directories below the limit are watched as a whole instead. Defaults to 16.
+    pub fn classpath_rerun_depth(&mut self, classpath_rerun_depth: usize) -> &mut Self {
+        self.classpath_rerun_depth = classpath_rerun_depth;
+        self
+    }
+
+    /// Adds every jar listed in the file at `p` to the classpath, such as a lockfile written by
+    /// a dependency resolver. The file lists one path per line; blank lines and lines starting
+    /// with `#` are ignored, and relative paths are resolved against the file's directory.
+    ///
+    /// Fails without adding anything if the file can't be read or a listed path doesn't exist.
+    pub fn classpath_from_file<P: AsRef<Path>>(&mut self, p: P) -> Result<&mut Self, Error> {
+        let p = p.as_ref();
+        let base = p.parent().unwrap_or(Path::new(""));
+
+        let mut entries = vec![];
+        for line in std::fs::read_to_string(p)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let entry = base.join(line);
+            if !entry.exists() {
+                return Err(Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!(
+                        "{} lists {}, which does not exist",
+                        p.display(),
+                        entry.display()
+                    ),
+                ));
+            }
+            entries.push(entry);
         }
 
-        if let Some(java_home) = &self.java_home {
-            cmd.arg("-java-home").arg(java_home);
+        self.cargo_output
+            .print_metadata(&format_args!("cargo:rerun-if-changed={}", p.display()));
+        Ok(self.classpaths(entries))
+    }
+
+    /// Prints `cargo:<key>=<value>` after every successful compile, for example to share the path
+    /// of the produced jar with crates that depend on the one running this build script.
+    ///
+    /// Cargo only passes such metadata on if the package running the build script declares a
+    /// `links` key in its `Cargo.toml`, such as `links = "mylib"`. The build scripts of its
+    /// direct dependents can then read the value from the `DEP_MYLIB_<KEY>` environment
+    /// variable, where `<KEY>` is `key` in upper case with `-` replaced by `_`.
+    pub fn emit_metadata_var(&mut self, key: &str, value: &str) -> &mut Self {
+        self.metadata_vars.push((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Registers a closure that is run with the output path after the next successful
+    /// [`Build::compile`]. An error returned from the closure becomes the result of `compile`.
+    pub fn after_compile<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnOnce(&Path) -> Result<(), Error> + Send + 'static,
+    {
+        *self.after_compile.get_mut().unwrap() = Some(Box::new(f));
+        self
+    }
+
+    /// Registers a closure that provides kotlinc when `kotlinc-jvm` is not found on `PATH`, for
+    /// example by downloading and extracting a compiler distribution. It is run at most once, and
+    /// the path it returns is used as the compiler executable, such as `<dist>/bin/kotlinc-jvm`.
+    /// An error returned from the closure becomes the result of compiling.
+    pub fn on_missing_compiler<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnOnce() -> Result<PathBuf, Error> + Send + 'static,
+    {
+        *self.on_missing_compiler.get_mut().unwrap() = Some(Box::new(f));
+        self
+    }
+
+    /// Registers a closure that rewrites the bytecode of every class kotlinc produced, for
+    /// lightweight instrumentation without a separate tool. It is called with the internal name
+    /// of each class, such as `com/example/Main`, and its bytes, and returns the bytes to write
+    /// back. An error returned from the closure becomes the result of compiling.
+    ///
+    /// Every class is read and written again after each compile, and jar outputs are extracted
+    /// and repackaged, so this adds noticeably to the build time of large modules.
+    pub fn transform_class<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&str, Vec<u8>) -> Result<Vec<u8>, Error> + Send + Sync + 'static,
+    {
+        self.transform_class = Some(Arc::new(f));
+        self
+    }
+
+    /// Registers a closure providing installation guidance, such as organization-specific setup
+    /// instructions, that is appended to the error returned when kotlinc cannot be found.
+    pub fn on_tool_not_found<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.on_tool_not_found = Some(Arc::new(f));
+        self
+    }
+
+    /// Adds a module output whose `internal` declarations are visible to the sources being
+    /// compiled, passed to kotlinc through `-Xfriend-paths`.
+    pub fn friend_path<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
+        self.friend_paths.push(p.as_ref().into());
+        self
+    }
+
+    /// Makes the sources being compiled a friend of the module built by `other` into
+    /// `other_output`, as tests are of the module they test. `other_output` is added to both the
+    /// classpath and the friend paths so its `internal` declarations are accessible, followed by
+    /// the classpath entries of `other` that aren't on the classpath yet.
+    pub fn friend_of(&mut self, other: &Build, other_output: &Path) -> &mut Self {
+        self.classpath(other_output).friend_path(other_output);
+        for entry in &other.classpath {
+            if !self.classpath.contains(entry) {
+                self.classpath.push(entry.clone());
+            }
         }
+        self
+    }
 
-        if self.include_runtime {
-            cmd.arg("-include-runtime");
+    /// Fails the compile with an error if kotlinc warns about a deprecated usage whose message
+    /// contains `symbol_substring`.
+    pub fn fail_on_deprecation<S: AsRef<str>>(&mut self, symbol_substring: S) -> &mut Self {
+        self.fail_on_deprecation
+            .push(symbol_substring.as_ref().to_owned());
+        self
+    }
+
+    /// Combines `other` into this configuration. Lists such as files and classpath entries from
+    /// `other` are appended, while any option `other` changed from its default overrides the
+    /// value configured here. The `after_compile` and `on_missing_compiler` hooks are not carried
+    /// over.
+    pub fn merge(&mut self, other: &Build) -> &mut Self {
+        self.files.extend(other.files.iter().cloned());
+        self.java_files.extend(other.java_files.iter().cloned());
+        self.source_roots.extend(other.source_roots.iter().cloned());
+        self.classpath.extend(other.classpath.iter().cloned());
+        self.module_path.extend(other.module_path.iter().cloned());
+        self.friend_paths.extend(other.friend_paths.iter().cloned());
+        self.fail_on_deprecation
+            .extend(other.fail_on_deprecation.iter().cloned());
+        self.raw_args_before_files
+            .extend(other.raw_args_before_files.iter().cloned());
+        self.raw_args_after_files
+            .extend(other.raw_args_after_files.iter().cloned());
+        self.dump_phases.extend(other.dump_phases.iter().cloned());
+        self.javac_args.extend(other.javac_args.iter().cloned());
+        for fragment in &other.fragments {
+            self.fragment(fragment);
+        }
+        self.fragment_sources
+            .extend(other.fragment_sources.iter().cloned());
+        self.common_sources
+            .extend(other.common_sources.iter().cloned());
+        self.fragment_refines
+            .extend(other.fragment_refines.iter().cloned());
+        self.metadata_vars
+            .extend(other.metadata_vars.iter().cloned());
+        for flag in &other.experimental_flags {
+            self.experimental_flag(*flag);
+        }
+        for flag in &other.inference_flags {
+            self.inference_flag(*flag);
         }
 
-        if self.no_jdk {
-            cmd.arg("-no-jdk");
+        if other.java_home.is_some() {
+            self.java_home.clone_from(&other.java_home);
         }
 
-        if self.no_reflect {
-            cmd.arg("-no-reflect");
+        if other.cache_key.is_some() {
+            self.cache_key.clone_from(&other.cache_key);
         }
 
-        if self.no_stdlib {
-            cmd.arg("-no-stdlib");
+        if other.source_encoding.is_some() {
+            self.source_encoding.clone_from(&other.source_encoding);
         }
 
-        if self.warnings_into_errors {
-            cmd.arg("-Werror");
+        if other.module_name.is_some() {
+            self.module_name.clone_from(&other.module_name);
         }
 
-        for file in &self.files {
-            println!("cargo:rerun-if-changed={:?}", file);
-            cmd.arg(file);
+        if other.build_file.is_some() {
+            self.build_file.clone_from(&other.build_file);
+        }
+
+        if other.toolchain.is_some() {
+            self.toolchain.clone_from(&other.toolchain);
+        }
+
+        if other.runtime_stdlib_jar.is_some() {
+            self.runtime_stdlib_jar
+                .clone_from(&other.runtime_stdlib_jar);
+        }
+
+        if other.profile_output.is_some() {
+            self.profile_output.clone_from(&other.profile_output);
         }
 
-        cmd.arg("-d").arg(output);
-        run(&mut cmd, "kotlinc-jvm", &self.cargo_output)
+        if other.min_java_version != 0 {
+            self.min_java_version = other.min_java_version;
+        }
+
+        if other.cargo_output.relative_to.is_some() {
+            self.cargo_output
+                .relative_to
+                .clone_from(&other.cargo_output.relative_to);
+        }
+
+        if other.cargo_output.log_file.is_some() {
+            self.cargo_output
+                .log_file
+                .clone_from(&other.cargo_output.log_file);
+        }
+
+        if other.cargo_output.line_filter.is_some() {
+            self.cargo_output
+                .line_filter
+                .clone_from(&other.cargo_output.line_filter);
+        }
+
+        self.cargo_output.debug |= other.cargo_output.debug;
+        self.cargo_output.dedupe_warnings |= other.cargo_output.dedupe_warnings;
+        self.cargo_output.fail_fast |= other.cargo_output.fail_fast;
+        if other.cargo_output.heartbeat.is_some() {
+            self.cargo_output.heartbeat = other.cargo_output.heartbeat;
+        }
+
+        if other.cargo_output.diagnostic_format != DiagnosticFormat::default() {
+            self.cargo_output.diagnostic_format = other.cargo_output.diagnostic_format;
+        }
+
+        if other.classpath_rerun_depth != DEFAULT_CLASSPATH_RERUN_DEPTH {
+            self.classpath_rerun_depth = other.classpath_rerun_depth;
+        }
+
+        if other.max_warnings.is_some() {
+            self.max_warnings = other.max_warnings;
+        }
+
+        if other.command_wrapper.is_some() {
+            self.command_wrapper.clone_from(&other.command_wrapper);
+        }
+
+        if other.transform_class.is_some() {
+            self.transform_class.clone_from(&other.transform_class);
+        }
+
+        if other.on_tool_not_found.is_some() {
+            self.on_tool_not_found.clone_from(&other.on_tool_not_found);
+        }
+
+        if other.backend != Backend::default() {
+            self.backend = other.backend;
+        }
+
+        if other.language_version.is_some() {
+            self.language_version = other.language_version;
+        }
+
+        if other.api_version.is_some() {
+            self.api_version = other.api_version;
+        }
+
+        if other.jspecify_annotations.is_some() {
+            self.jspecify_annotations = other.jspecify_annotations;
+        }
+
+        if other.assertions.is_some() {
+            self.assertions = other.assertions;
+        }
+
+        if other.backend_threads.is_some() {
+            self.backend_threads = other.backend_threads;
+        }
+
+        if other.string_concat.is_some() {
+            self.string_concat = other.string_concat;
+        }
+
+        if other.runtime_variant != RuntimeVariant::default() {
+            self.runtime_variant = other.runtime_variant;
+        }
+
+        if other.classpath_order != ClasspathOrder::default() {
+            self.classpath_order = other.classpath_order;
+        }
+
+        if other.jar_compression != JarCompression::default() {
+            self.jar_compression = other.jar_compression;
+        }
+
+        if other.checksum_algorithm != ChecksumAlgorithm::default() {
+            self.checksum_algorithm = other.checksum_algorithm;
+        }
+
+        self.inherit_java_home &= other.inherit_java_home;
+        self.include_runtime |= other.include_runtime;
+        self.disable_friend_modules |= other.disable_friend_modules;
+        self.emit_checksum |= other.emit_checksum;
+        self.extra_checks |= other.extra_checks;
+        self.fallback_to_k1 |= other.fallback_to_k1;
+        self.no_jdk |= other.no_jdk;
+        self.no_reflect |= other.no_reflect;
+        self.no_stdlib |= other.no_stdlib;
+        self.profile |= other.profile;
+        self.report_output_files |= other.report_output_files;
+        self.reproducible |= other.reproducible;
+        self.validate_packages |= other.validate_packages;
+        self.skip_prerelease_check |= other.skip_prerelease_check;
+        self.auto_java_home |= other.auto_java_home;
+        self.append_to_jar |= other.append_to_jar;
+        self.render_diagnostic_names |= other.render_diagnostic_names;
+        self.allow_empty_sources |= other.allow_empty_sources;
+        self.optimize &= other.optimize;
+        self.extended_checkers |= other.extended_checkers;
+        self.emit_sources_jar |= other.emit_sources_jar;
+        self.include_java_sources |= other.include_java_sources;
+        self.warn_classpath_overlap &= other.warn_classpath_overlap;
+        self.strict_out_dir |= other.strict_out_dir;
+        self.expect_actual_classes |= other.expect_actual_classes;
+        self.disable_scripting_plugin |= other.disable_scripting_plugin;
+        self.use_javac |= other.use_javac;
+        self.ir_inliner |= other.ir_inliner;
+        self.emit_jvm_type_annotations |= other.emit_jvm_type_annotations;
+        self.canonical_command |= other.canonical_command;
+        self.warnings_into_errors |= other.warnings_into_errors;
+        self
+    }
+
+    /// Silences everything this crate would print: cargo metadata, forwarded compiler warnings,
+    /// debug output and the compiler's stdout.
+    pub fn quiet(&mut self) -> &mut Self {
+        self.cargo_output.metadata = false;
+        self.cargo_output.warnings = false;
+        self.cargo_output.debug = false;
+        self.cargo_output.output = OutputKind::Discard;
+        self
+    }
+
+    /// Prints every command this crate runs and how it exited. Enabled by default when the
+    /// `KOTLIN_RS_DEBUG` or `CC_ENABLE_DEBUG_OUTPUT` environment variable is set.
+    pub fn debug(&mut self, debug: bool) -> &mut Self {
+        self.cargo_output.debug = debug;
+        self
+    }
+
+    /// Forwards each distinct diagnostic only once, and prints a `... (N more identical)`
+    /// summary for those that were repeated once kotlinc has finished, which keeps logs readable
+    /// when a single problem triggers the same warning many times.
+    pub fn dedupe_warnings(&mut self, dedupe_warnings: bool) -> &mut Self {
+        self.cargo_output.dedupe_warnings = dedupe_warnings;
+        self
+    }
+
+    /// Stops kotlinc as soon as it reports its first error instead of waiting for it to finish,
+    /// which saves time on large modules that fail early. Everything kotlinc wrote up to that
+    /// point is still forwarded, but later errors are not reported.
+    ///
+    /// On Unix, kotlinc is run in a process group of its own so the JVM started by its launcher
+    /// script is stopped as well. As a result, pressing Ctrl-C in the terminal doesn't interrupt
+    /// kotlinc directly: it keeps running until it writes output after the build was stopped.
+    /// Elsewhere, only the launcher is stopped, and compiling returns once the JVM has exited.
+    pub fn fail_fast(&mut self, fail_fast: bool) -> &mut Self {
+        self.cargo_output.fail_fast = fail_fast;
+        self
+    }
+
+    /// Prints a `still compiling (Ns elapsed)` line to stdout every `interval` while kotlinc
+    /// runs, so a long compile that reports nothing doesn't look stuck. This works with warnings
+    /// disabled, but nothing is printed when kotlinc's output isn't forwarded, as with
+    /// [`Build::quiet`].
+    pub fn heartbeat(&mut self, interval: Option<Duration>) -> &mut Self {
+        self.cargo_output.heartbeat = interval;
+        self
+    }
+
+    /// Rewrites the paths in kotlinc's diagnostics to be relative to `base`, both in the warnings
+    /// forwarded to cargo and in the [`Diagnostic::file`] of parsed diagnostics. Paths outside of
+    /// `base` are left as-is.
+    pub fn relativize_diagnostics_to<P: AsRef<Path>>(&mut self, base: P) -> &mut Self {
+        let base = base.as_ref();
+        self.cargo_output.relative_to =
+            Some(std::path::absolute(base).unwrap_or_else(|_| base.to_path_buf()));
+        self
+    }
+
+    /// Selects how kotlinc's diagnostics are forwarded while compiling. Defaults to
+    /// [`DiagnosticFormat::CargoWarning`].
+    pub fn diagnostic_format(&mut self, diagnostic_format: DiagnosticFormat) -> &mut Self {
+        self.cargo_output.diagnostic_format = diagnostic_format;
+        self
+    }
+
+    /// Registers a predicate deciding which lines of kotlinc's output are forwarded, such as to
+    /// hide warnings about generated sources that can't be fixed. Lines for which it returns
+    /// `false` are not printed, but are still parsed, so they count towards
+    /// [`Build::max_warnings`] and are returned by [`Build::compile_with_diagnostics`].
+    pub fn filter_diagnostics<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.cargo_output.line_filter = Some(LineFilter(Arc::new(f)));
+        self
+    }
+
+    /// Writes everything kotlinc prints to the file at `path`, independently of what is forwarded
+    /// to cargo. The file is truncated at the start of every compile, and the output of a retry
+    /// through [`Build::fallback_to_k1`] is appended.
+    pub fn log_output_to<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.cargo_output.log_file = Some(path.as_ref().into());
+        self
+    }
+
+    /// Removes every source input: files, Java files, source roots, fragment sources and common
+    /// sources. The build file of [`Build::build_file`] is kept.
+    pub fn clear_files(&mut self) -> &mut Self {
+        self.files.clear();
+        self.java_files.clear();
+        self.source_roots.clear();
+        self.fragment_sources.clear();
+        self.common_sources.clear();
+        self
+    }
+
+    pub fn clear_classpath(&mut self) -> &mut Self {
+        self.classpath.clear();
+        self
+    }
+
+    /// Returns the entries added through [`Build::module_path`], in the order added.
+    pub fn get_module_path(&self) -> &[PathBuf] {
+        &self.module_path
+    }
+
+    /// Returns the Kotlin source files added through [`Build::file`], in the order added.
+    pub fn get_files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Returns the Java source files added through [`Build::java_file`], in the order added.
+    pub fn get_java_files(&self) -> &[PathBuf] {
+        &self.java_files
+    }
+
+    /// Returns the multiplatform fragments declared through [`Build::fragment`].
+    pub fn get_fragments(&self) -> &[String] {
+        &self.fragments
+    }
+
+    /// Returns the fragment of each source added through [`Build::fragment_source`].
+    pub fn get_fragment_sources(&self) -> &[(String, PathBuf)] {
+        &self.fragment_sources
+    }
+
+    /// Returns the common sources found by [`Build::common_dir`].
+    pub fn get_common_sources(&self) -> &[PathBuf] {
+        &self.common_sources
+    }
+
+    /// Returns the refinements declared through [`Build::fragment_refines`].
+    pub fn get_fragment_refines(&self) -> &[(String, String)] {
+        &self.fragment_refines
+    }
+
+    /// Returns the directories added through [`Build::source_root`].
+    pub fn get_source_roots(&self) -> &[PathBuf] {
+        &self.source_roots
+    }
+
+    /// Returns the classpath entries in the order added, before [`Build::classpath_order`] applies.
+    pub fn get_classpath(&self) -> &[PathBuf] {
+        &self.classpath
+    }
+
+    /// Returns the paths added through [`Build::friend_path`].
+    pub fn get_friend_paths(&self) -> &[PathBuf] {
+        &self.friend_paths
+    }
+
+    /// Returns the substrings registered through [`Build::fail_on_deprecation`].
+    pub fn get_fail_on_deprecation(&self) -> &[String] {
+        &self.fail_on_deprecation
+    }
+
+    /// Returns the arguments added through [`Build::raw_args_before_files`].
+    pub fn get_raw_args_before_files(&self) -> &[OsString] {
+        &self.raw_args_before_files
+    }
+
+    /// Returns the arguments added through [`Build::raw_args_after_files`].
+    pub fn get_raw_args_after_files(&self) -> &[OsString] {
+        &self.raw_args_after_files
+    }
+
+    /// Returns the flags enabled through [`Build::experimental_flag`].
+    pub fn get_experimental_flags(&self) -> &[ExperimentalFlag] {
+        &self.experimental_flags
+    }
+
+    /// Returns the variables added through [`Build::emit_metadata_var`], in the order added.
+    pub fn get_metadata_vars(&self) -> &[(String, String)] {
+        &self.metadata_vars
+    }
+
+    /// Returns the arguments added through [`Build::javac_arg`].
+    pub fn get_javac_args(&self) -> &[String] {
+        &self.javac_args
+    }
+
+    /// Returns the phases added through [`Build::dump_phase`].
+    pub fn get_dump_phases(&self) -> &[String] {
+        &self.dump_phases
+    }
+
+    /// Returns the flags enabled through [`Build::inference_flag`].
+    pub fn get_inference_flags(&self) -> &[InferenceFlag] {
+        &self.inference_flags
+    }
+
+    /// Returns the explicitly configured Java home, not the one inherited from `JAVA_HOME`.
+    pub fn get_java_home(&self) -> Option<&Path> {
+        self.java_home.as_deref()
+    }
+
+    /// Returns the directory set with [`Build::relativize_diagnostics_to`].
+    pub fn get_relativize_diagnostics_to(&self) -> Option<&Path> {
+        self.cargo_output.relative_to.as_deref()
+    }
+
+    /// Returns the file set with [`Build::log_output_to`].
+    pub fn get_log_output_to(&self) -> Option<&Path> {
+        self.cargo_output.log_file.as_deref()
+    }
+
+    /// Returns whether repeated warnings are forwarded only once.
+    pub fn get_dedupe_warnings(&self) -> bool {
+        self.cargo_output.dedupe_warnings
+    }
+
+    /// Returns whether kotlinc is stopped at its first error.
+    pub fn get_fail_fast(&self) -> bool {
+        self.cargo_output.fail_fast
+    }
+
+    /// Returns the interval of [`Build::heartbeat`], if set.
+    pub fn get_heartbeat(&self) -> Option<Duration> {
+        self.cargo_output.heartbeat
+    }
+
+    /// Returns whether every command run and its exit status are printed.
+    pub fn get_debug(&self) -> bool {
+        self.cargo_output.debug
+    }
+
+    /// Returns how forwarded diagnostics are printed.
+    pub fn get_diagnostic_format(&self) -> DiagnosticFormat {
+        self.cargo_output.diagnostic_format
+    }
+
+    /// Returns whether [`Build::profile`] is enabled.
+    pub fn get_profile(&self) -> bool {
+        self.profile
+    }
+
+    /// Returns the standard library jar set with [`Build::runtime_stdlib_jar`].
+    pub fn get_runtime_stdlib_jar(&self) -> Option<&Path> {
+        self.runtime_stdlib_jar.as_deref()
+    }
+
+    /// Returns the toolchain directory set with [`Build::with_toolchain`].
+    pub fn get_toolchain(&self) -> Option<&Path> {
+        self.toolchain.as_deref()
+    }
+
+    /// Returns the file [`Build::profile`] writes its measurements to, if any.
+    pub fn get_profile_output(&self) -> Option<&Path> {
+        self.profile_output.as_deref()
+    }
+
+    /// Returns the key set with [`Build::cache_key`].
+    pub fn get_cache_key(&self) -> Option<&str> {
+        self.cache_key.as_deref()
+    }
+
+    /// Returns the charset set with [`Build::source_encoding`].
+    pub fn get_source_encoding(&self) -> Option<&str> {
+        self.source_encoding.as_deref()
+    }
+
+    /// Returns the module name set with [`Build::module_name`].
+    pub fn get_module_name(&self) -> Option<&str> {
+        self.module_name.as_deref()
+    }
+
+    /// Returns the build file set with [`Build::build_file`].
+    pub fn get_build_file(&self) -> Option<&Path> {
+        self.build_file.as_deref()
+    }
+
+    /// Returns the selected compiler frontend.
+    pub fn get_backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Returns the order the classpath is passed to kotlinc in.
+    pub fn get_classpath_order(&self) -> ClasspathOrder {
+        self.classpath_order
+    }
+
+    /// Returns the runtime bundled by [`Build::include_runtime`].
+    pub fn get_runtime_variant(&self) -> RuntimeVariant {
+        self.runtime_variant
+    }
+
+    /// Returns the version set with [`Build::language_version`], unresolved.
+    pub fn get_language_version(&self) -> Option<KotlinVersion> {
+        self.language_version
+    }
+
+    /// Returns the version set with [`Build::api_version`], unresolved.
+    pub fn get_api_version(&self) -> Option<KotlinVersion> {
+        self.api_version
+    }
+
+    /// Returns the mode set with [`Build::jspecify_annotations`].
+    pub fn get_jspecify_annotations(&self) -> Option<NullabilityMode> {
+        self.jspecify_annotations
+    }
+
+    /// Returns the mode set with [`Build::assertions`].
+    pub fn get_assertions(&self) -> Option<AssertionsMode> {
+        self.assertions
+    }
+
+    /// Returns the mode set with [`Build::string_concat`].
+    pub fn get_string_concat(&self) -> Option<StringConcatMode> {
+        self.string_concat
+    }
+
+    /// Returns the algorithm used by [`Build::emit_checksum`].
+    pub fn get_checksum_algorithm(&self) -> ChecksumAlgorithm {
+        self.checksum_algorithm
+    }
+
+    /// Returns how the entries of rewritten jars are compressed.
+    pub fn get_jar_compression(&self) -> JarCompression {
+        self.jar_compression
+    }
+
+    /// Returns the program and arguments set with [`Build::command_wrapper`].
+    pub fn get_command_wrapper(&self) -> Option<(&OsStr, &[OsString])> {
+        self.command_wrapper
+            .as_ref()
+            .map(|(program, args)| (program.as_os_str(), args.as_slice()))
+    }
+
+    /// Returns the oldest JDK version [`Build::auto_java_home`] accepts.
+    pub fn get_min_java_version(&self) -> u32 {
+        self.min_java_version
+    }
+
+    /// Returns the limit set with [`Build::max_warnings`].
+    pub fn get_max_warnings(&self) -> Option<usize> {
+        self.max_warnings
+    }
+
+    /// Returns the thread count set with [`Build::backend_threads`].
+    pub fn get_backend_threads(&self) -> Option<usize> {
+        self.backend_threads
+    }
+
+    /// Returns how deep classpath directories are watched for changes.
+    pub fn get_classpath_rerun_depth(&self) -> usize {
+        self.classpath_rerun_depth
+    }
+
+    /// Returns whether `JAVA_HOME` is used when no Java home is configured.
+    pub fn get_inherit_java_home(&self) -> bool {
+        self.inherit_java_home
+    }
+
+    /// Returns whether [`Build::include_runtime`] is enabled.
+    pub fn get_include_runtime(&self) -> bool {
+        self.include_runtime
+    }
+
+    /// Returns whether [`Build::disable_friend_modules`] is enabled.
+    pub fn get_disable_friend_modules(&self) -> bool {
+        self.disable_friend_modules
+    }
+
+    /// Returns whether [`Build::emit_checksum`] is enabled.
+    pub fn get_emit_checksum(&self) -> bool {
+        self.emit_checksum
+    }
+
+    /// Returns whether [`Build::extra_checks`] is enabled.
+    pub fn get_extra_checks(&self) -> bool {
+        self.extra_checks
+    }
+
+    /// Returns whether [`Build::fallback_to_k1`] is enabled.
+    pub fn get_fallback_to_k1(&self) -> bool {
+        self.fallback_to_k1
+    }
+
+    /// Returns whether [`Build::no_jdk`] is enabled.
+    pub fn get_no_jdk(&self) -> bool {
+        self.no_jdk
+    }
+
+    /// Returns whether [`Build::no_reflect`] is enabled.
+    pub fn get_no_reflect(&self) -> bool {
+        self.no_reflect
+    }
+
+    /// Returns whether [`Build::no_stdlib`] is enabled.
+    pub fn get_no_stdlib(&self) -> bool {
+        self.no_stdlib
+    }
+
+    /// Returns whether [`Build::report_output_files`] is enabled.
+    pub fn get_report_output_files(&self) -> bool {
+        self.report_output_files
+    }
+
+    /// Returns whether [`Build::reproducible`] is enabled.
+    pub fn get_reproducible(&self) -> bool {
+        self.reproducible
+    }
+
+    /// Returns whether [`Build::validate_packages`] is enabled.
+    pub fn get_validate_packages(&self) -> bool {
+        self.validate_packages
+    }
+
+    /// Returns whether [`Build::skip_prerelease_check`] is enabled.
+    pub fn get_skip_prerelease_check(&self) -> bool {
+        self.skip_prerelease_check
+    }
+
+    /// Returns whether [`Build::auto_java_home`] is enabled.
+    pub fn get_auto_java_home(&self) -> bool {
+        self.auto_java_home
+    }
+
+    /// Returns whether [`Build::append_to_jar`] is enabled.
+    pub fn get_append_to_jar(&self) -> bool {
+        self.append_to_jar
+    }
+
+    /// Returns whether [`Build::render_diagnostic_names`] is enabled.
+    pub fn get_render_diagnostic_names(&self) -> bool {
+        self.render_diagnostic_names
+    }
+
+    /// Returns whether [`Build::allow_empty_sources`] is enabled.
+    pub fn get_allow_empty_sources(&self) -> bool {
+        self.allow_empty_sources
+    }
+
+    /// Returns whether [`Build::optimize`] is enabled.
+    pub fn get_optimize(&self) -> bool {
+        self.optimize
+    }
+
+    /// Returns whether [`Build::extended_checkers`] is enabled.
+    pub fn get_extended_checkers(&self) -> bool {
+        self.extended_checkers
+    }
+
+    /// Returns whether [`Build::emit_sources_jar`] is enabled.
+    pub fn get_emit_sources_jar(&self) -> bool {
+        self.emit_sources_jar
+    }
+
+    /// Returns whether [`Build::include_java_sources`] is enabled.
+    pub fn get_include_java_sources(&self) -> bool {
+        self.include_java_sources
+    }
+
+    /// Returns whether [`Build::warn_classpath_overlap`] is enabled.
+    pub fn get_warn_classpath_overlap(&self) -> bool {
+        self.warn_classpath_overlap
+    }
+
+    /// Returns whether [`Build::strict_out_dir`] is enabled.
+    pub fn get_strict_out_dir(&self) -> bool {
+        self.strict_out_dir
+    }
+
+    /// Returns whether [`Build::expect_actual_classes`] is enabled.
+    pub fn get_expect_actual_classes(&self) -> bool {
+        self.expect_actual_classes
+    }
+
+    /// Returns whether [`Build::disable_scripting_plugin`] is enabled.
+    pub fn get_disable_scripting_plugin(&self) -> bool {
+        self.disable_scripting_plugin
+    }
+
+    /// Returns whether [`Build::use_javac`] is enabled.
+    pub fn get_use_javac(&self) -> bool {
+        self.use_javac
+    }
+
+    /// Returns whether [`Build::ir_inliner`] is enabled.
+    pub fn get_ir_inliner(&self) -> bool {
+        self.ir_inliner
+    }
+
+    /// Returns whether [`Build::emit_jvm_type_annotations`] is enabled.
+    pub fn get_emit_jvm_type_annotations(&self) -> bool {
+        self.emit_jvm_type_annotations
+    }
+
+    /// Returns whether [`Build::canonical_command`] is enabled.
+    pub fn get_canonical_command(&self) -> bool {
+        self.canonical_command
+    }
+
+    /// Returns whether [`Build::warnings_into_errors`] is enabled.
+    pub fn get_warnings_into_errors(&self) -> bool {
+        self.warnings_into_errors
+    }
+
+    pub fn compile(&self, output: &str) -> Result<(), Error> {
+        if self.is_cached(output)? {
+            return Ok(());
+        }
+
+        self.start_log()?;
+        let previous = self.keep_previous_jar(output)?;
+        self.execute_with_fallback(output, &[], None)?;
+        self.finish(output, previous.as_ref())
+    }
+
+    /// Compiles like [`Build::compile`] and returns, for every source file, the files that
+    /// kotlinc generated from it. Skipping kotlinc because of [`Build::cache_key`] returns an
+    /// empty map.
+    pub fn compile_with_outputs(
+        &self,
+        output: &str,
+    ) -> Result<HashMap<PathBuf, Vec<PathBuf>>, Error> {
+        if self.is_cached(output)? {
+            return Ok(HashMap::new());
+        }
+
+        let extra_args: &[&str] = if self.report_output_files {
+            &[]
+        } else {
+            &["-Xreport-output-files"]
+        };
+
+        self.start_log()?;
+        let previous = self.keep_previous_jar(output)?;
+        let stderr = self.execute_with_fallback(output, extra_args, None)?;
+        self.finish(output, previous.as_ref())?;
+
+        Ok(output_files::parse(&stderr))
+    }
+
+    /// Compiles to a jar in a fresh temporary directory and returns the directory together with
+    /// the jar's path. Dropping the returned [`TempDir`] removes the directory and everything in
+    /// it, which avoids manual cleanup in tests and scratch runs.
+    ///
+    /// The directory is created below `OUT_DIR`, or the system's temporary directory outside of
+    /// build scripts.
+    pub fn compile_temp(&self) -> Result<(TempDir, PathBuf), Error> {
+        let dir = TempDir(unique_scratch_dir("kotlin-temp")?);
+        let artifact = dir.path().join("output.jar");
+        self.compile(&artifact.to_string_lossy())?;
+        Ok((dir, artifact))
+    }
+
+    /// Compiles `source` on its own, with the options and classpath of this builder but none of
+    /// its sources, and returns the bytes of the class `class_name`, such as `com.example.Foo`
+    /// or `FooKt` for the top-level functions of the snippet. The snippet and its classes are
+    /// written to a temporary directory that is removed afterwards.
+    pub fn compile_snippet(&self, class_name: &str, source: &str) -> Result<Vec<u8>, Error> {
+        let scratch = unique_scratch_dir("kotlin-snippet")?;
+        let source_file = scratch.path().join("Snippet.kt");
+        std::fs::write(&source_file, source)?;
+
+        let mut snippet = Build::new();
+        snippet.merge(self).clear_files().file(&source_file);
+        snippet.build_file = None;
+        snippet.cache_key = None;
+        snippet.emit_sources_jar = false;
+        snippet.metadata_vars.clear();
+        // Rerunning the build script when the deleted snippet changes would make it run every time
+        snippet.cargo_output = self.cargo_output.clone();
+        snippet.cargo_output.metadata = false;
+
+        let classes = scratch.path().join("classes");
+        snippet.compile(&classes.to_string_lossy())?;
+
+        let class_file = classes.join(format!("{}.class", class_name.replace('.', "/")));
+        std::fs::read(&class_file).map_err(|e| {
+            Error::new(
+                ErrorKind::IOError,
+                format!("Failed to read class {} of the snippet: {}", class_name, e),
+            )
+        })
+    }
+
+    /// Compiles like [`Build::compile`] and returns the path of the output together with the
+    /// path of the sources jar, which is only created with [`Build::emit_sources_jar`].
+    pub fn compile_with_sources_jar(
+        &self,
+        output: &str,
+    ) -> Result<(PathBuf, Option<PathBuf>), Error> {
+        self.compile(output)?;
+        let sources_jar = self.emit_sources_jar.then(|| sources_jar_path(output));
+        Ok((PathBuf::from(output), sources_jar))
+    }
+
+    /// Compiles to the directory `classes_dir` and packages its files into a zip archive at
+    /// `archive`, with the entries laid out as selected by `layout`, for deployment targets that
+    /// don't expect a standard jar. Returns the path of the archive.
+    ///
+    /// The archive has no manifest unless the layout stores a file as `META-INF/MANIFEST.MF`.
+    pub fn compile_and_archive(
+        &self,
+        classes_dir: &Path,
+        archive: &Path,
+        layout: ArchiveLayout,
+    ) -> Result<PathBuf, Error> {
+        if classes_dir.extension().is_some_and(|e| e == "jar") {
+            return Err(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!(
+                    "compile_and_archive compiles to a directory, not to the jar {}",
+                    classes_dir.display()
+                ),
+            ));
+        }
+        self.compile(&classes_dir.to_string_lossy())?;
+
+        let mut files = vec![];
+        let mut sources = HashMap::new();
+        for entry in jar::entries_of(classes_dir)? {
+            let name = layout.entry_name(&entry);
+            if let Some(previous) = sources.insert(name.clone(), entry.clone()) {
+                return Err(Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!(
+                        "{} and {} would both be stored as {} in {}",
+                        previous,
+                        entry,
+                        name,
+                        archive.display()
+                    ),
+                ));
+            }
+            files.push((name, classes_dir.join(&entry)));
+        }
+
+        let java_home = self.resolve_java_home();
+        self.jar_tool(java_home.as_deref())
+            .package_files(&files, archive)?;
+        Ok(archive.to_path_buf())
+    }
+
+    /// Compiles like [`Build::compile`] and additionally returns the warnings kotlinc reported,
+    /// which are empty when [`Build::cache_key`] skips kotlinc.
+    pub fn compile_checked(&self, output: &str) -> Result<CompileOutcome, Error> {
+        if self.is_cached(output)? {
+            return Ok(CompileOutcome {
+                artifact: PathBuf::from(output),
+                warnings: vec![],
+            });
+        }
+
+        self.start_log()?;
+        let previous = self.keep_previous_jar(output)?;
+        let reported = self.execute_with_fallback(output, &[], None)?;
+        self.finish(output, previous.as_ref())?;
+
+        Ok(CompileOutcome {
+            artifact: PathBuf::from(output),
+            warnings: diagnostics::parse(&reported)
+                .into_iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Warning)
+                .collect(),
+        })
+    }
+
+    /// Compiles like [`Build::compile`], but writes kotlinc's output to `sink` instead of
+    /// forwarding it to cargo, and returns the path of the output. Lines kotlinc writes to stderr
+    /// are passed on as they are printed, while its stdout follows once it has finished.
+    ///
+    /// Lines are written unformatted, regardless of [`Build::diagnostic_format`] and
+    /// [`Build::quiet`], but [`Build::filter_diagnostics`] still applies. Nothing is written when
+    /// [`Build::cache_key`] skips kotlinc.
+    pub fn compile_to_writer<W: Write>(&self, output: &str, mut sink: W) -> Result<PathBuf, Error> {
+        if self.is_cached(output)? {
+            return Ok(PathBuf::from(output));
+        }
+
+        self.start_log()?;
+        let previous = self.keep_previous_jar(output)?;
+        self.execute_with_fallback(output, &[], Some(&mut sink))?;
+        self.finish(output, previous.as_ref())?;
+        Ok(PathBuf::from(output))
+    }
+
+    /// Compiles like [`Build::compile`] and returns every diagnostic kotlinc reported, including
+    /// warnings from a successful compile. The outcomes are:
+    ///
+    /// - `Ok` without any [`Severity::Error`] diagnostic: the compile succeeded, and an empty
+    ///   `Vec` means it did so without reporting anything. Skipping kotlinc because of
+    ///   [`Build::cache_key`] also returns an empty `Vec`.
+    /// - `Ok` with at least one [`Severity::Error`] diagnostic: kotlinc rejected the sources.
+    /// - `Err` with [`ErrorKind::ToolNotFound`]: kotlinc could not be found, so no diagnostics
+    ///   exist.
+    /// - `Err` of any other kind: the configuration is invalid, kotlinc failed without reporting
+    ///   an error diagnostic, or the output could not be post-processed.
+    ///
+    /// With [`Build::fallback_to_k1`], the diagnostics are those of the K1 retry if K2 failed.
+    pub fn compile_with_diagnostics(&self, output: &str) -> Result<Vec<Diagnostic>, Error> {
+        if self.is_cached(output)? {
+            return Ok(vec![]);
+        }
+
+        self.start_log()?;
+        let previous = self.keep_previous_jar(output)?;
+        let (status, captured) = self.run_with_fallback(output, &[], None)?;
+        let diagnostics = diagnostics::parse(&captured.text());
+
+        match status {
+            Ok(()) => {
+                self.check_diagnostics(&diagnostics)?;
+                self.finish(output, previous.as_ref())?;
+                Ok(diagnostics)
+            }
+            Err(_) if diagnostics.iter().any(|d| d.severity == Severity::Error) => Ok(diagnostics),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Starts compiling like [`Build::compile`] and returns an iterator over the diagnostics
+    /// kotlinc reports, yielding each as soon as it is printed rather than once kotlinc has
+    /// finished. The diagnostics are not forwarded to cargo, and [`DiagnosticsIter::finish`]
+    /// returns whether the compile succeeded.
+    ///
+    /// kotlinc runs on a separate thread, and the output isn't post-processed: options such as
+    /// [`Build::reproducible`], [`Build::emit_checksum`] and [`Build::after_compile`] don't
+    /// apply, and neither do [`Build::fail_on_deprecation`] and [`Build::max_warnings`].
+    pub fn compile_diagnostics_iter(&self, output: &str) -> Result<DiagnosticsIter, Error> {
+        self.start_log()?;
+        let mut cmd = self.command(output, self.backend)?;
+        *self.last_command.lock().unwrap() = Some(display_command(&cmd));
+
+        let cargo_output = self.cargo_output.clone();
+        let hint = self.on_tool_not_found.clone();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let compile = std::thread::spawn(move || {
+            let program = PathBuf::from(cmd.get_program());
+            let mut sink = diagnostics::DiagnosticSender::new(sender);
+            let (status, _) =
+                run_and_capture_output(&mut cmd, program, &cargo_output, Some(&mut sink));
+            status.map_err(|e| match (e.kind(), hint) {
+                (ErrorKind::ToolNotFound, Some(hint)) => e.with_note(hint()),
+                _ => e,
+            })
+        });
+
+        Ok(DiagnosticsIter::new(receiver, compile))
+    }
+
+    /// Compiles to the jar at `output` and runs it with `java -jar`, using the `java` of the
+    /// configured Java home if there is one. The program's stdout and stderr are inherited, and
+    /// its exit status is returned as-is.
+    ///
+    /// The jar has to declare a `Main-Class`, which kotlinc does when a `main` function is
+    /// compiled. Unless the program avoids the Kotlin standard library, also enable
+    /// [`Build::include_runtime`].
+    pub fn compile_and_run(
+        &self,
+        output: &str,
+        program_args: &[&str],
+    ) -> Result<ExitStatus, Error> {
+        if !output.ends_with(".jar") {
+            return Err(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!("`compile_and_run` requires a jar output, got {}", output),
+            ));
+        }
+
+        self.compile(output)?;
+
+        let java_home = self.resolve_java_home();
+        let manifest = self
+            .jar_tool(java_home.as_deref())
+            .read_manifest(Path::new(output))?
+            .unwrap_or_default();
+        if !manifest.lines().any(|line| line.starts_with("Main-Class:")) {
+            return Err(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!("{} is not runnable as it declares no Main-Class", output),
+            ));
+        }
+
+        let java = match java_home {
+            Some(java_home) => java_home.join("bin").join("java"),
+            None => PathBuf::from("java"),
+        };
+        let mut cmd = Command::new(&java);
+        cmd.arg("-jar").arg(output).args(program_args);
+        run_inherited(&mut cmd, &java, &self.cargo_output)
+    }
+
+    /// Writes a launcher to `script_path` that runs `main_class` from `jar` with
+    /// `java -cp <jar> <main_class>`, passing on its own arguments. The launcher is a batch file
+    /// on Windows and an executable `sh` script elsewhere. It uses the `java` of the Java home
+    /// set through [`Build::java_home`] if there is one, and the `java` on `PATH` otherwise;
+    /// `JAVA_HOME` and discovered JDKs are ignored.
+    ///
+    /// The jar is referenced by its absolute path, so the launcher keeps working from any
+    /// directory but not once the jar is moved.
+    pub fn emit_launcher_script(
+        &self,
+        jar: &Path,
+        script_path: &Path,
+        main_class: &str,
+    ) -> Result<(), Error> {
+        if !jar.is_file() {
+            return Err(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!("Jar {} does not exist", jar.display()),
+            ));
+        }
+        let is_class_name = |c: char| c.is_alphanumeric() || c == '.' || c == '_' || c == '$';
+        if main_class.is_empty() || !main_class.chars().all(is_class_name) {
+            return Err(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!("`{}` is not a valid main class name", main_class),
+            ));
+        }
+
+        let jar = std::path::absolute(jar)?;
+        // A Java home found on the build machine would be meaningless where the launcher runs
+        let java = match &self.java_home {
+            Some(java_home) => java_home.join("bin").join("java"),
+            None => PathBuf::from("java"),
+        };
+
+        let script = if cfg!(windows) {
+            format!(
+                "@echo off\r\n\"{}\" -cp \"{}\" {} %*\r\n",
+                java.display(),
+                jar.display(),
+                main_class
+            )
+        } else {
+            format!(
+                "#!/bin/sh\nexec {} -cp {} {} \"$@\"\n",
+                shell_quote(&java.to_string_lossy()),
+                shell_quote(&jar.to_string_lossy()),
+                shell_quote(main_class)
+            )
+        };
+        std::fs::write(script_path, script)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(script_path, std::fs::Permissions::from_mode(0o755))?;
+        }
+
+        Ok(())
+    }
+
+    /// Selects which Kotlin runtime jars [`Build::jar_from_classes`] bundles. Defaults to
+    /// [`RuntimeVariant::Full`].
+    pub fn runtime_variant(&mut self, runtime_variant: RuntimeVariant) -> &mut Self {
+        self.runtime_variant = runtime_variant;
+        self
+    }
+
+    /// Bundles the given standard library jar when [`Build::jar_from_classes`] includes the
+    /// runtime, instead of looking up the jars of the installed Kotlin distribution. Only this
+    /// jar is bundled, regardless of [`Build::runtime_variant`], which suits air-gapped builds
+    /// without a distribution in `KOTLIN_HOME`.
+    ///
+    /// Packaging fails if the jar doesn't exist or has no entries below `kotlin/`.
+    pub fn runtime_stdlib_jar<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
+        self.runtime_stdlib_jar = Some(p.as_ref().into());
+        self
+    }
+
+    /// Uses the Kotlin distribution at `kotlin_home` instead of the one found through `PATH` or
+    /// `KOTLIN_HOME`, for machines with several installed: kotlinc is run from its `bin`
+    /// directory, and [`Build::jar_from_classes`] bundles the runtime jars of its `lib`
+    /// directory. Compiling fails if either directory is missing.
+    pub fn with_toolchain<P: AsRef<Path>>(&mut self, kotlin_home: P) -> &mut Self {
+        self.toolchain = Some(kotlin_home.as_ref().into());
+        self
+    }
+
+    /// Packages a directory of previously compiled classes, such as the output of compiling to
+    /// a directory, into a jar at `jar_path` without recompiling, and returns the jar's path.
+    ///
+    /// With `include_runtime`, the Kotlin standard library of the installed compiler is bundled
+    /// like kotlinc's `-include-runtime` would, together with the extensions selected by
+    /// [`Build::runtime_variant`]. The jars are taken from the `lib` directory of the
+    /// distribution selected by [`Build::with_toolchain`], otherwise of the one in `KOTLIN_HOME`
+    /// or the one providing `kotlinc` on `PATH`.
+    pub fn jar_from_classes(
+        &self,
+        classes_dir: &Path,
+        jar_path: &Path,
+        include_runtime: bool,
+    ) -> Result<PathBuf, Error> {
+        let java_home = self.resolve_java_home();
+        let jar_tool = self.jar_tool(java_home.as_deref());
+
+        let runtime = if let (true, Some(stdlib)) = (include_runtime, &self.runtime_stdlib_jar) {
+            if !stdlib.is_file() {
+                return Err(Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!("Runtime stdlib jar {} does not exist", stdlib.display()),
+                ));
+            }
+            if !jar_tool
+                .list(stdlib)?
+                .iter()
+                .any(|entry| entry.starts_with("kotlin/"))
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!(
+                        "Runtime stdlib jar {} contains no `kotlin/` entries",
+                        stdlib.display()
+                    ),
+                ));
+            }
+            self.cargo_output
+                .print_metadata(&format_args!("cargo:rerun-if-changed={}", stdlib.display()));
+            vec![stdlib.clone()]
+        } else if let (true, Some(home)) = (include_runtime, &self.toolchain) {
+            if toolchain::library(home, "kotlin-stdlib.jar").is_none() {
+                return Err(Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!("Toolchain {} has no lib/kotlin-stdlib.jar", home.display()),
+                ));
+            }
+            self.runtime_variant
+                .libraries()
+                .iter()
+                .filter_map(|name| toolchain::library(home, name))
+                .collect()
+        } else if include_runtime {
+            self.cargo_output
+                .print_metadata(&"cargo:rerun-if-env-changed=KOTLIN_HOME");
+            let home = toolchain::kotlin_home()
+                .filter(|home| toolchain::library(home, "kotlin-stdlib.jar").is_some())
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::ToolNotFound,
+                        "Failed to find kotlin-stdlib.jar. Set KOTLIN_HOME to the Kotlin \
+                         distribution or put kotlinc on PATH.",
+                    )
+                })?;
+            self.runtime_variant
+                .libraries()
+                .iter()
+                .filter_map(|name| toolchain::library(&home, name))
+                .collect()
+        } else {
+            vec![]
+        };
+
+        jar_tool.package(classes_dir, jar_path, &runtime)?;
+        Ok(jar_path.to_path_buf())
+    }
+
+    /// Lists the class files of a compiled output, either a classes directory or a jar, as paths
+    /// relative to its root, such as `com/example/Main.class`. The paths are sorted.
+    pub fn list_classes(&self, output: &Path) -> Result<Vec<PathBuf>, Error> {
+        Ok(self
+            .output_entries(output)?
+            .into_iter()
+            .filter(|entry| entry.ends_with(".class"))
+            .map(PathBuf::from)
+            .collect())
+    }
+
+    /// Compiles like [`Build::compile`] and returns the names of every file in the output, such
+    /// as `META-INF/main.kotlin_module` and `com/example/Main.class`, sorted so they can be
+    /// compared against a golden listing regardless of the order kotlinc wrote them in.
+    pub fn compile_and_list_sorted(&self, output: &str) -> Result<Vec<String>, Error> {
+        self.compile(output)?;
+        self.output_entries(Path::new(output))
+    }
+
+    /// Checks whether kotlinc accepts `flag` by compiling an empty source file with it, and
+    /// returns `false` if kotlinc reports the flag as unknown or unsupported. Results are cached
+    /// per flag for the lifetime of this builder.
+    ///
+    /// The probe runs in a scratch directory below `OUT_DIR`, or the system's temporary
+    /// directory outside of build scripts.
+    pub fn probe_flag(&self, flag: &str) -> Result<bool, Error> {
+        if let Some(supported) = self.probed_flags.lock().unwrap().get(flag) {
+            return Ok(*supported);
+        }
+
+        let scratch = unique_scratch_dir("kotlin-probe")?;
+        let source = scratch.path().join("Probe.kt");
+        std::fs::write(&source, "")?;
+
+        let mut cmd = self.base_command()?;
+        cmd.arg(flag)
+            .arg(&source)
+            .arg("-d")
+            .arg(scratch.path().join("out"));
+        let program = PathBuf::from(cmd.get_program());
+        // The probe's diagnostics are only inspected, never forwarded
+        let mut cargo_output = self.cargo_output.clone();
+        cargo_output.warnings = false;
+        cargo_output.output = OutputKind::Discard;
+        cargo_output.log_file = None;
+        cargo_output.fail_fast = false;
+        let (status, captured) = run_and_capture_output(&mut cmd, program, &cargo_output, None);
+        if let Err(e) = status {
+            if !matches!(e.kind(), ErrorKind::ToolExecError) {
+                return Err(self.tool_not_found_hint(e));
+            }
+        }
+
+        let output = captured.text();
+        let supported = !output.lines().any(|line| {
+            line.contains(flag)
+                && (line.contains("not supported")
+                    || line.contains("invalid flag")
+                    || line.contains("invalid argument"))
+        });
+        self.probed_flags
+            .lock()
+            .unwrap()
+            .insert(flag.to_owned(), supported);
+        Ok(supported)
+    }
+
+    /// Returns the kotlinc invocation most recently run by one of the compile methods, rendered
+    /// like [`Build::command_line`], or `None` if nothing has been run yet. The command is
+    /// recorded before kotlinc is started, so it is also available after a failed compile.
+    pub fn last_command(&self) -> Option<String> {
+        self.last_command.lock().unwrap().clone()
+    }
+
+    /// Returns the kotlinc invocation [`Build::compile`] would run for `output`, rendered as a
+    /// line that can be pasted into a POSIX shell. Arguments containing spaces or other shell
+    /// metacharacters are single-quoted.
+    ///
+    /// Building the command validates the configuration and may query the compiler version, so
+    /// this fails in the same cases `compile` fails before running kotlinc.
+    pub fn command_line(&self, output: &str) -> Result<String, Error> {
+        Ok(display_command(&self.command(output, self.backend)?))
+    }
+
+    /// Checks the configuration without compiling and returns every problem found: conflicting
+    /// options, input paths that don't exist, a compiler that can't be run, and options the
+    /// installed compiler doesn't support. Compiling stops at the first of these instead, and
+    /// leaves missing inputs for kotlinc to report.
+    pub fn validate(&self) -> Result<(), Vec<Error>> {
+        let mut problems = self.configuration_problems();
+
+        let inputs = self
+            .files
+            .iter()
+            .chain(&self.java_files)
+            .chain(&self.source_roots)
+            .chain(&self.classpath)
+            .chain(&self.friend_paths)
+            .chain(&self.build_file)
+            .chain(&self.java_home);
+        for input in inputs {
+            if !input.exists() {
+                problems.push(Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!("{} does not exist", input.display()),
+                ));
+            }
+        }
+
+        match self.compiler_version() {
+            Ok(version) => problems.extend(self.version_problems(self.backend, version)),
+            Err(e) => problems.push(e),
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    fn check_configuration(&self) -> Result<(), Error> {
+        match self.configuration_problems().into_iter().next() {
+            Some(problem) => Err(problem),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns every conflict between the configured options.
+    fn configuration_problems(&self) -> Vec<Error> {
+        let mut problems = vec![];
+
+        if self.disable_friend_modules && !self.friend_paths.is_empty() {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                "`disable_friend_modules` cannot be combined with `friend_path` entries",
+            ));
+        }
+
+        if self.extended_checkers && self.backend == Backend::K1 {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                "`extended_checkers` requires the K2 frontend and cannot be combined with \
+                 `Backend::K1`",
+            ));
+        }
+
+        if self.extra_checks && self.backend == Backend::K1 {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                "`extra_checks` requires the K2 frontend on Kotlin 2.0 and cannot be combined with \
+                 `Backend::K1`",
+            ));
+        }
+
+        if self.ir_inliner && self.backend == Backend::K1 {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                "`ir_inliner` requires the K2 frontend and cannot be combined with `Backend::K1`",
+            ));
+        }
+
+        if let Some(home) = &self.toolchain {
+            for dir in ["bin", "lib"] {
+                if !home.join(dir).is_dir() {
+                    problems.push(Error::new(
+                        ErrorKind::InvalidConfiguration,
+                        format!(
+                            "Toolchain {} is not a Kotlin distribution as it has no `{}` directory",
+                            home.display(),
+                            dir
+                        ),
+                    ));
+                }
+            }
+        }
+
+        if let Some(charset) = &self.source_encoding {
+            if !options::is_known_charset(charset) {
+                problems.push(Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!("`source_encoding` {:?} is not a known charset", charset),
+                ));
+            }
+        }
+
+        let referenced = self
+            .fragment_sources
+            .iter()
+            .map(|(fragment, _)| fragment)
+            .chain(
+                self.fragment_refines
+                    .iter()
+                    .flat_map(|(from, to)| [from, to]),
+            );
+        let mut undeclared = referenced
+            .filter(|fragment| !self.fragments.contains(fragment))
+            .collect::<Vec<_>>();
+        undeclared.sort();
+        undeclared.dedup();
+        if !undeclared.is_empty() {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!(
+                    "`fragment_source` and `fragment_refines` use undeclared fragments: {}",
+                    undeclared
+                        .iter()
+                        .map(|fragment| format!("`{}`", fragment))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            ));
+        }
+
+        if self.disable_scripting_plugin
+            && self
+                .files
+                .iter()
+                .any(|file| file.extension().is_some_and(|e| e == "kts"))
+        {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                "`disable_scripting_plugin` cannot be combined with script (`.kts`) files",
+            ));
+        }
+
+        let has_java_sources = !self.java_files.is_empty()
+            || (self.include_java_sources && !self.source_roots.is_empty());
+        if self.use_javac && !has_java_sources {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                "`use_javac` requires Java sources from `java_file` or `include_java_sources`",
+            ));
+        }
+
+        if !self.javac_args.is_empty() && !self.use_javac {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                "`javac_arg` requires `use_javac`",
+            ));
+        }
+
+        if let Some(arg) = self.javac_args.iter().find(|arg| arg.contains(',')) {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!("javac argument {:?} cannot contain a comma", arg),
+            ));
+        }
+
+        if self.backend_threads == Some(0) {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                "`backend_threads` has to be at least 1",
+            ));
+        }
+
+        if self.build_file.is_some() && self.emit_sources_jar {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                "`emit_sources_jar` cannot be combined with `build_file`, whose sources are \
+                 unknown to this crate",
+            ));
+        }
+
+        if self.build_file.is_some()
+            && (!self.files.is_empty()
+                || !self.java_files.is_empty()
+                || !self.source_roots.is_empty()
+                || !self.classpath.is_empty()
+                || !self.module_path.is_empty())
+        {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                "`build_file` cannot be combined with `file`, `java_file`, `source_root`, \
+                 `classpath` or `module_path` entries",
+            ));
+        }
+
+        for entry in self.module_path.iter().filter(|entry| !entry.exists()) {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!("Module path entry {} does not exist", entry.display()),
+            ));
+        }
+
+        problems
+    }
+
+    /// With [`Build::strict_out_dir`], checks that `output` is inside `OUT_DIR` when running under
+    /// Cargo.
+    fn check_out_dir(&self, output: &str) -> Result<(), Error> {
+        if !self.strict_out_dir || std::env::var_os("CARGO").is_none() {
+            return Ok(());
+        }
+
+        let out_dir = std::env::var_os("OUT_DIR").ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidConfiguration,
+                "`strict_out_dir` requires OUT_DIR to be set when running under Cargo",
+            )
+        })?;
+        check_inside_out_dir(output, Path::new(&out_dir))
+    }
+
+    /// Returns every option the given compiler version doesn't support.
+    fn version_problems(&self, backend: Backend, version: CompilerVersion) -> Vec<Error> {
+        let mut problems = vec![];
+
+        if backend.flags(version).is_none() {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!(
+                    "kotlinc {} does not support the {:?} backend",
+                    version, backend
+                ),
+            ));
+        }
+
+        let backend_language_version = backend
+            .flags(version)
+            .is_some_and(|flags| flags.contains(&"-language-version"));
+        if self.language_version.is_some() && backend_language_version {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!(
+                    "`language_version` cannot be combined with the {:?} backend on kotlinc {}, \
+                     which selects the language version itself",
+                    backend, version
+                ),
+            ));
+        }
+
+        for flag in &self.experimental_flags {
+            if version < flag.since() {
+                problems.push(Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!(
+                        "kotlinc {} does not support {}, which requires kotlinc {}",
+                        version,
+                        flag.flag(),
+                        flag.since()
+                    ),
+                ));
+            }
+        }
+
+        for flag in &self.inference_flags {
+            if version < flag.since() {
+                problems.push(Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!(
+                        "kotlinc {} does not support {}, which requires kotlinc {}",
+                        version,
+                        flag.flag(),
+                        flag.since()
+                    ),
+                ));
+            }
+        }
+
+        if let Some(mode) = self.string_concat {
+            if version < mode.since() {
+                problems.push(Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!(
+                        "kotlinc {} does not support -Xstring-concat={}, which requires kotlinc {}",
+                        version,
+                        mode.name(),
+                        mode.since()
+                    ),
+                ));
+            }
+        }
+
+        if self.backend_threads.is_some() && version < CompilerVersion::new(1, 6, 20) {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!(
+                    "kotlinc {} does not support -Xbackend-threads, which requires kotlinc 1.6.20",
+                    version
+                ),
+            ));
+        }
+
+        if self.emit_jvm_type_annotations && version < CompilerVersion::new(1, 4, 0) {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!(
+                    "kotlinc {} does not support -Xemit-jvm-type-annotations, which requires \
+                     kotlinc 1.4.0",
+                    version
+                ),
+            ));
+        }
+
+        if self.ir_inliner && version < CompilerVersion::new(2, 0, 0) {
+            problems.push(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!(
+                    "kotlinc {} does not support -Xir-inliner, which requires kotlinc 2.0.0",
+                    version
+                ),
+            ));
+        }
+
+        problems
+    }
+
+    fn command(&self, output: &str, backend: Backend) -> Result<Command, Error> {
+        self.check_configuration()?;
+        self.check_out_dir(output)?;
+
+        let mut cmd = self.base_command()?;
+
+        if let Some(charset) = &self.source_encoding {
+            cmd.arg(format!("-J-Dfile.encoding={}", charset));
+        }
+
+        if backend != Backend::Default
+            || !self.experimental_flags.is_empty()
+            || !self.inference_flags.is_empty()
+            || self.string_concat.is_some()
+            || self.backend_threads.is_some()
+            || self.ir_inliner
+            || self.emit_jvm_type_annotations
+        {
+            let version = self.compiler_version()?;
+            if let Some(problem) = self.version_problems(backend, version).into_iter().next() {
+                return Err(problem);
+            }
+            cmd.args(backend.flags(version).unwrap_or_default());
+        }
+
+        let versions = [
+            ("-language-version", self.language_version),
+            ("-api-version", self.api_version),
+        ];
+        for (flag, version) in versions {
+            if let Some(version) = version {
+                cmd.arg(flag)
+                    .arg(version.resolve(|| self.compiler_version())?);
+            }
+        }
+
+        if !self.classpath.is_empty() {
+            let classpath = std::env::join_paths(self.classpath_order.sort(&self.classpath))
+                .map_err(|e| {
+                    Error::new(
+                        ErrorKind::InvalidConfiguration,
+                        format!("invalid `classpath` entry: {}", e),
+                    )
+                })?;
+
+            cmd.arg("-cp").arg(classpath);
+
+            if let (true, Some(module_name)) = (self.warn_classpath_overlap, &self.module_name) {
+                for jar in self
+                    .classpath
+                    .iter()
+                    .filter(|entry| is_build_of_module(entry, module_name))
+                {
+                    self.cargo_output.print_warning(&format_args!(
+                        "Classpath entry {} looks like a build of module `{}`, which is being \
+                         compiled from source and may lead to duplicate classes",
+                        jar.display(),
+                        module_name
+                    ));
+                }
+            }
+
+            for dir in self.classpath.iter().filter(|entry| entry.is_dir()) {
+                let (files, skipped) =
+                    fs_helpers::walk_files_to_depth(dir, self.classpath_rerun_depth)?;
+                for path in files.iter().chain(&skipped) {
+                    self.cargo_output
+                        .print_metadata(&format_args!("cargo:rerun-if-changed={}", path.display()));
+                }
+            }
+        }
+
+        if !self.module_path.is_empty() {
+            let module_path = std::env::join_paths(&self.module_path).map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!("invalid `module_path` entry: {}", e),
+                )
+            })?;
+
+            let mut arg = OsString::from("-Xmodule-path=");
+            arg.push(module_path);
+            cmd.arg(arg);
+
+            for entry in &self.module_path {
+                self.cargo_output
+                    .print_metadata(&format_args!("cargo:rerun-if-changed={}", entry.display()));
+            }
+        }
+
+        if let Some(module_name) = &self.module_name {
+            cmd.arg("-module-name").arg(module_name);
+        }
+
+        if !self.friend_paths.is_empty() {
+            let friend_paths = self
+                .unordered(self.friend_paths.iter().map(|p| p.display().to_string()))
+                .join(",");
+
+            cmd.arg(format!("-Xfriend-paths={}", friend_paths));
+        }
+
+        if self.disable_friend_modules {
+            cmd.arg("-Xfriend-modules-disabled");
+        }
+
+        if let Some(java_home) = self.resolve_java_home() {
+            cmd.arg("-java-home").arg(java_home);
+        }
+
+        if self.include_runtime {
+            cmd.arg("-include-runtime");
+        }
+
+        if self.no_jdk {
+            cmd.arg("-no-jdk");
+        }
+
+        if self.no_reflect {
+            cmd.arg("-no-reflect");
+        }
+
+        if self.no_stdlib {
+            cmd.arg("-no-stdlib");
+        }
+
+        if self.report_output_files {
+            cmd.arg("-Xreport-output-files");
+        }
+
+        if self.extra_checks {
+            let version = self.compiler_version()?;
+            if version >= CompilerVersion::new(2, 0, 0) {
+                // Dropped when retrying with K1 through `fallback_to_k1`
+                if !self.extended_checkers && backend != Backend::K1 {
+                    cmd.arg("-Xuse-fir-extended-checkers");
+                }
+            } else if version >= CompilerVersion::new(1, 5, 0) {
+                cmd.arg("-Xextended-compiler-checks");
+            } else {
+                self.cargo_output.print_warning(&format_args!(
+                    "kotlinc {} does not support extra checks, ignoring `extra_checks`",
+                    version
+                ));
+            }
+        }
+
+        if self.expect_actual_classes {
+            let version = self.compiler_version()?;
+            if version >= CompilerVersion::new(1, 9, 20) {
+                cmd.arg("-Xexpect-actual-classes");
+            } else {
+                self.cargo_output.print_warning(&format_args!(
+                    "kotlinc {} does not support expect/actual classes, ignoring \
+                     `expect_actual_classes`",
+                    version
+                ));
+            }
+        }
+
+        cmd.args(self.unordered(self.experimental_flags.iter().map(|flag| flag.flag())));
+        cmd.args(self.unordered(self.inference_flags.iter().map(|flag| flag.flag())));
+
+        if let Some(mode) = self.string_concat {
+            cmd.arg(format!("-Xstring-concat={}", mode.name()));
+        }
+
+        if let Some(mode) = self.assertions {
+            cmd.arg(format!("-Xassertions={}", mode.name()));
+        }
+
+        if let Some(n) = self.backend_threads {
+            cmd.arg(format!("-Xbackend-threads={}", n));
+        }
+
+        if !self.dump_phases.is_empty() {
+            let phases = self.unordered(self.dump_phases.iter().cloned()).join(",");
+            cmd.arg(format!("-Xphases-to-dump={}", phases));
+        }
+
+        if let Some(mode) = self.jspecify_annotations {
+            cmd.arg(format!("-Xjspecify-annotations={}", mode.name()));
+        }
+
+        if self.profile {
+            cmd.arg("-Xreport-perf");
+            if let Some(profile_output) = &self.profile_output {
+                cmd.arg(format!("-Xdump-perf={}", profile_output.display()));
+            }
+        }
+
+        // Dropped when retrying with K1 through `fallback_to_k1`
+        if self.extended_checkers && backend != Backend::K1 {
+            cmd.arg("-Xuse-fir-extended-checkers");
+        }
+        if self.ir_inliner && backend != Backend::K1 {
+            cmd.arg("-Xir-inliner");
+        }
+
+        if self.skip_prerelease_check {
+            cmd.arg("-Xskip-prerelease-check");
+        }
+
+        if self.render_diagnostic_names {
+            cmd.arg("-Xrender-internal-diagnostic-names");
+        }
+
+        if self.allow_empty_sources {
+            cmd.arg("-Xallow-no-source-files");
+        }
+
+        if !self.optimize {
+            cmd.arg("-Xno-optimize");
+        }
+
+        if self.disable_scripting_plugin {
+            cmd.arg("-Xdisable-default-scripting-plugin");
+        }
+
+        if self.use_javac {
+            cmd.arg("-Xuse-javac").arg("-Xcompile-java");
+        }
+
+        if !self.javac_args.is_empty() {
+            cmd.arg(format!("-Xjavac-arguments={}", self.javac_args.join(",")));
+        }
+
+        if self.emit_jvm_type_annotations {
+            cmd.arg("-Xemit-jvm-type-annotations");
+        }
+
+        if self.warnings_into_errors {
+            cmd.arg("-Werror");
+        }
+
+        if !self.fragments.is_empty() || !self.common_sources.is_empty() {
+            cmd.arg("-Xmulti-platform");
+        }
+
+        if !self.common_sources.is_empty() {
+            let common_sources = self
+                .unordered(self.common_sources.iter().map(|p| p.display().to_string()))
+                .join(",");
+            cmd.arg(format!("-Xcommon-sources={}", common_sources));
+        }
+
+        if !self.fragments.is_empty() {
+            let fragments = self.unordered(self.fragments.iter().cloned()).join(",");
+            cmd.arg(format!("-Xfragments={}", fragments));
+
+            if !self.fragment_sources.is_empty() {
+                let fragment_sources = self
+                    .unordered(
+                        self.fragment_sources
+                            .iter()
+                            .map(|(fragment, path)| format!("{}:{}", fragment, path.display())),
+                    )
+                    .join(",");
+                cmd.arg(format!("-Xfragment-sources={}", fragment_sources));
+            }
+
+            if !self.fragment_refines.is_empty() {
+                let fragment_refines = self
+                    .unordered(
+                        self.fragment_refines
+                            .iter()
+                            .map(|(from, to)| format!("{}:{}", from, to)),
+                    )
+                    .join(",");
+                cmd.arg(format!("-Xfragment-refines={}", fragment_refines));
+            }
+        }
+
+        cmd.args(&self.raw_args_before_files);
+
+        for file in self.files.iter().chain(&self.java_files) {
+            self.cargo_output
+                .print_metadata(&format_args!("cargo:rerun-if-changed={}", file.display()));
+            cmd.arg(file);
+        }
+
+        for root in &self.source_roots {
+            let files = sources::source_files(root, self.include_java_sources)?;
+
+            if self.validate_packages {
+                let mismatches = sources::package_mismatches(root, &files)?;
+                if !mismatches.is_empty() {
+                    return Err(Error::new(
+                        ErrorKind::InvalidConfiguration,
+                        format!(
+                            "Source files in {} do not match their packages:\n{}",
+                            root.display(),
+                            mismatches.join("\n")
+                        ),
+                    ));
+                }
+            }
+
+            self.cargo_output
+                .print_metadata(&format_args!("cargo:rerun-if-changed={}", root.display()));
+            cmd.args(files);
+        }
+
+        for (_, path) in &self.fragment_sources {
+            self.cargo_output
+                .print_metadata(&format_args!("cargo:rerun-if-changed={}", path.display()));
+            cmd.arg(path);
+        }
+
+        cmd.args(&self.raw_args_after_files);
+
+        if let Some(build_file) = &self.build_file {
+            self.cargo_output.print_metadata(&format_args!(
+                "cargo:rerun-if-changed={}",
+                build_file.display()
+            ));
+            cmd.arg(format!("-Xbuild-file={}", build_file.display()));
+        } else {
+            cmd.arg("-d").arg(output);
+        }
+
+        Ok(cmd)
+    }
+
+    /// Runs kotlinc like [`Build::run_with_fallback`] and fails unless it succeeded and its
+    /// diagnostics pass [`Build::fail_on_deprecation`] and [`Build::max_warnings`]. Returns
+    /// everything kotlinc printed.
+    fn execute_with_fallback(
+        &self,
+        output: &str,
+        extra_args: &[&str],
+        sink: Option<&mut dyn Write>,
+    ) -> Result<String, Error> {
+        let (status, captured) = self.run_with_fallback(output, extra_args, sink)?;
+        status?;
+
+        let output = captured.text();
+        self.check_diagnostics(&diagnostics::parse(&output))?;
+        Ok(output)
+    }
+
+    /// Runs kotlinc with the configured backend, retrying with K1 if kotlinc exits
+    /// unsuccessfully and [`Build::fallback_to_k1`] is enabled. Returns the status and output of
+    /// the last attempt, whose diagnostics are left for the caller to check.
+    fn run_with_fallback(
+        &self,
+        output: &str,
+        extra_args: &[&str],
+        mut sink: Option<&mut dyn Write>,
+    ) -> Result<(Result<(), Error>, CapturedOutput), Error> {
+        let mut cmd = self.command(output, self.backend)?;
+        cmd.args(extra_args);
+
+        match self.run_compiler(&mut cmd, reborrow(&mut sink)) {
+            // Stopping at the first error through `fail_fast` is not a failure of K2
+            (Err(e), captured)
+                if self.fallback_to_k1
+                    && self.backend == Backend::K2
+                    && matches!(e.kind(), ErrorKind::ToolExecError)
+                    && !captured.stopped_early =>
+            {
+                self.cargo_output.print_warning(&format_args!(
+                    "Compiling with K2 failed, retrying with K1: {}",
+                    e
+                ));
+
+                let mut cmd = self.command(output, Backend::K1)?;
+                cmd.args(extra_args);
+                Ok(self.run_compiler(&mut cmd, sink))
+            }
+            result => Ok(result),
+        }
+    }
+
+    fn run_compiler(
+        &self,
+        cmd: &mut Command,
+        sink: Option<&mut dyn Write>,
+    ) -> (Result<(), Error>, CapturedOutput) {
+        let program = PathBuf::from(cmd.get_program());
+        *self.last_command.lock().unwrap() = Some(display_command(cmd));
+        let (status, output) = run_and_capture_output(cmd, program, &self.cargo_output, sink);
+        (status.map_err(|e| self.tool_not_found_hint(e)), output)
+    }
+
+    fn check_diagnostics(&self, diagnostics: &[Diagnostic]) -> Result<(), Error> {
+        for diagnostic in diagnostics {
+            if diagnostic.severity != Severity::Warning
+                || !diagnostic.message.contains("deprecated")
+            {
+                continue;
+            }
+
+            if let Some(symbol) = self
+                .fail_on_deprecation
+                .iter()
+                .find(|symbol| diagnostic.message.contains(symbol.as_str()))
+            {
+                return Err(Error::new(
+                    ErrorKind::ToolExecError,
+                    format!(
+                        "Usage of deprecated `{}` is not allowed: {}",
+                        symbol, diagnostic
+                    ),
+                ));
+            }
+        }
+
+        if let Some(max_warnings) = self.max_warnings {
+            let warnings = diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Warning)
+                .count();
+            if warnings > max_warnings {
+                return Err(Error::new(
+                    ErrorKind::ToolExecError,
+                    format!(
+                        "kotlinc reported {} warnings, more than the {} allowed by `max_warnings`",
+                        warnings, max_warnings
+                    ),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the command invoking kotlinc, through the wrapper if one is configured.
+    fn base_command(&self) -> Result<Command, Error> {
+        let compiler = self.compiler()?;
+        Ok(match &self.command_wrapper {
+            Some((wrapper, args)) => {
+                let mut cmd = Command::new(wrapper);
+                cmd.args(args).arg(compiler);
+                cmd
+            }
+            None => Command::new(compiler),
+        })
+    }
+
+    /// Collects the values of flags whose order kotlinc ignores, which are sorted with
+    /// [`Build::canonical_command`] and kept in the order they were added otherwise.
+    fn unordered<I>(&self, values: I) -> Vec<String>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let mut values = values.into_iter().map(Into::into).collect::<Vec<String>>();
+        if self.canonical_command {
+            values.sort();
+        }
+        values
+    }
+
+    /// Returns the kotlinc executable to run, which is the one of [`Build::with_toolchain`] if
+    /// set, and otherwise `kotlinc-jvm` unless it is missing from `PATH` and
+    /// [`Build::on_missing_compiler`] provides another one.
+    fn compiler(&self) -> Result<PathBuf, Error> {
+        self.compiler
+            .get_or_init(|| {
+                if let Some(home) = &self.toolchain {
+                    return Ok(toolchain::compiler_in(home));
+                }
+                let on_missing_compiler = self.on_missing_compiler.lock().unwrap().take();
+                match on_missing_compiler {
+                    Some(on_missing_compiler) if toolchain::find_compiler().is_none() => {
+                        on_missing_compiler()
+                    }
+                    _ => Ok(PathBuf::from("kotlinc-jvm")),
+                }
+            })
+            .clone()
+    }
+
+    fn compiler_version(&self) -> Result<CompilerVersion, Error> {
+        self.compiler_version
+            .get_or_init(|| {
+                let mut cmd = self.base_command()?;
+                cmd.arg("-version");
+                let program = PathBuf::from(cmd.get_program());
+                let stderr = run_silently_and_capture_stderr(&mut cmd, program, &self.cargo_output)
+                    .map_err(|e| self.tool_not_found_hint(e))?;
+                let stderr = String::from_utf8_lossy(&stderr);
+
+                let compiler = self.compiler()?;
+                CompilerVersion::parse(&stderr).ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::ToolExecError,
+                        format!(
+                            "Failed to parse the version of {} from {:?}",
+                            compiler.display(),
+                            stderr
+                        ),
+                    )
+                })
+            })
+            .clone()
+    }
+
+    /// Appends the guidance of [`Build::on_tool_not_found`] to errors about a missing kotlinc.
+    fn tool_not_found_hint(&self, e: Error) -> Error {
+        match (e.kind(), &self.on_tool_not_found) {
+            (ErrorKind::ToolNotFound, Some(hint)) => e.with_note(hint()),
+            _ => e,
+        }
+    }
+
+    fn jar_tool(&self, java_home: Option<&Path>) -> JarTool<'_> {
+        JarTool::new(java_home, &self.cargo_output).compression(self.jar_compression)
+    }
+
+    fn resolve_java_home(&self) -> Option<PathBuf> {
+        self.resolve_java_home_from(&|name| std::env::var_os(name))
+    }
+
+    /// Like [`Build::resolve_java_home`], but reads `JAVA_HOME` through `env`.
+    fn resolve_java_home_from(&self, env: &dyn Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+        if let Some(java_home) = &self.java_home {
+            return Some(java_home.clone());
+        }
+
+        if self.auto_java_home {
+            self.cargo_output
+                .print_metadata(&"cargo:rerun-if-env-changed=JAVA_HOME");
+            let discovered = self.discovered_java_home.get_or_init(|| {
+                let discovered = java::find_java_home(self.min_java_version);
+                if discovered.is_none() {
+                    self.cargo_output.print_warning(&format_args!(
+                        "No JDK with version {} or newer found, ignoring `auto_java_home`",
+                        self.min_java_version
+                    ));
+                }
+                discovered
+            });
+            if let Some(java_home) = discovered {
+                return Some(java_home.clone());
+            }
+        }
+
+        if self.inherit_java_home {
+            self.cargo_output
+                .print_metadata(&"cargo:rerun-if-env-changed=JAVA_HOME");
+            return env("JAVA_HOME")
+                .filter(|java_home| !java_home.is_empty())
+                .map(PathBuf::from);
+        }
+
+        None
+    }
+
+    /// Truncates the file configured with [`Build::log_output_to`] at the start of a compile.
+    fn start_log(&self) -> Result<(), Error> {
+        if let Some(log_file) = &self.cargo_output.log_file {
+            std::fs::File::create(log_file)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the sorted names of the files in a classes directory or jar, separated by `/`.
+    fn output_entries(&self, output: &Path) -> Result<Vec<String>, Error> {
+        let mut entries = if output.extension().is_some_and(|e| e == "jar") {
+            let java_home = self.resolve_java_home();
+            self.jar_tool(java_home.as_deref()).list(output)?
+        } else {
+            jar::entries_of(output)?
+        };
+
+        entries.retain(|entry| !entry.ends_with('/'));
+        entries.sort();
+        Ok(entries)
+    }
+
+    /// With [`Build::append_to_jar`], copies an existing output jar aside before kotlinc
+    /// overwrites it. The copy is removed when the returned directory is dropped.
+    fn keep_previous_jar(&self, output: &str) -> Result<Option<ScratchDir>, Error> {
+        let output = Path::new(output);
+        if !self.append_to_jar || output.extension().is_none_or(|e| e != "jar") || !output.exists()
+        {
+            return Ok(None);
+        }
+
+        let previous = ScratchDir::new(jar::with_suffix(output, ".previous"))?;
+        std::fs::copy(output, previous.path().join("previous.jar"))?;
+        Ok(Some(previous))
+    }
+
+    /// Returns whether `output` was produced with the current [`Build::cache_key`], in which case
+    /// compiling is skipped. The configuration is still validated and the cargo metadata printed.
+    fn is_cached(&self, output: &str) -> Result<bool, Error> {
+        let Some(key) = &self.cache_key else {
+            return Ok(false);
+        };
+
+        let sidecar = jar::with_suffix(Path::new(output), ".cache-key");
+        if Path::new(output).exists()
+            && std::fs::read_to_string(&sidecar).is_ok_and(|stored| stored == *key)
+        {
+            // Still validates the configuration and prints the rerun-if-changed lines
+            self.command(output, self.backend)?;
+            self.print_output_metadata(output)?;
+            return Ok(true);
+        }
+        // A failed compile must not leave a matching key behind
+        let _ = std::fs::remove_file(sidecar);
+        Ok(false)
+    }
+
+    /// Prints the cargo metadata describing `output`: its checksum with [`Build::emit_checksum`]
+    /// and the variables of [`Build::emit_metadata_var`]. Cargo discards the metadata of earlier
+    /// runs, so this is also needed when [`Build::cache_key`] skips the compile.
+    fn print_output_metadata(&self, output: &str) -> Result<(), Error> {
+        for line in self.output_metadata(output)? {
+            self.cargo_output.print_metadata(&line);
+        }
+        Ok(())
+    }
+
+    /// Returns the `cargo:` lines printed by [`Build::print_output_metadata`].
+    fn output_metadata(&self, output: &str) -> Result<Vec<String>, Error> {
+        let mut lines = vec![];
+
+        if self.emit_checksum && output.ends_with(".jar") {
+            let digest = self.checksum_algorithm.hex_digest(Path::new(output))?;
+            lines.push(format!(
+                "cargo:{}={}",
+                self.checksum_algorithm.name(),
+                digest
+            ));
+        }
+
+        for (key, value) in &self.metadata_vars {
+            lines.push(format!("cargo:{}={}", key, value));
+        }
+
+        Ok(lines)
+    }
+
+    fn finish(&self, output: &str, previous: Option<&ScratchDir>) -> Result<(), Error> {
+        if let Some(transform) = &self.transform_class {
+            let output = Path::new(output);
+            if output.extension().is_some_and(|e| e == "jar") {
+                let java_home = self.resolve_java_home();
+                let jar_tool = self.jar_tool(java_home.as_deref());
+                let classes = ScratchDir::new(jar::with_suffix(output, ".classes"))?;
+                jar_tool.extract(output, classes.path())?;
+                transform_classes(classes.path(), transform)?;
+                jar_tool.package(classes.path(), output, &[])?;
+            } else {
+                transform_classes(output, transform)?;
+            }
+        }
+
+        if let Some(previous) = previous {
+            let java_home = self.resolve_java_home();
+            let duplicates = self
+                .jar_tool(java_home.as_deref())
+                .merge_previous(&previous.path().join("previous.jar"), Path::new(output))?;
+            if !duplicates.is_empty() {
+                self.cargo_output.print_warning(&format_args!(
+                    "Replaced {} existing entries of {}: {}",
+                    duplicates.len(),
+                    output,
+                    duplicates.join(", ")
+                ));
+            }
+        }
+
+        if self.reproducible && output.ends_with(".jar") {
+            let java_home = self.resolve_java_home();
+            self.jar_tool(java_home.as_deref())
+                .make_reproducible(Path::new(output))?;
+        }
+
+        if self.emit_sources_jar {
+            let files = [&self.files[..], &self.java_files[..]].concat();
+            let entries =
+                sources::source_entries(&files, &self.source_roots, self.include_java_sources)?;
+            let java_home = self.resolve_java_home();
+            self.jar_tool(java_home.as_deref())
+                .package_files(&entries, &sources_jar_path(output))?;
+        }
+
+        if self.emit_checksum && output.ends_with(".jar") {
+            let output = Path::new(output);
+            let algorithm = self.checksum_algorithm.name();
+            let digest = self.checksum_algorithm.hex_digest(output)?;
+            let file_name = output.file_name().unwrap_or_default().to_string_lossy();
+
+            let mut sidecar = output.as_os_str().to_owned();
+            sidecar.push(".");
+            sidecar.push(algorithm);
+            std::fs::write(sidecar, format!("{}  {}\n", digest, file_name))?;
+        }
+
+        self.print_output_metadata(output)?;
+
+        if let Some(after_compile) = self.after_compile.lock().unwrap().take() {
+            after_compile(Path::new(output))?;
+        }
+
+        // Only recorded once everything succeeded, so a failed step is retried by the next run
+        if let Some(key) = &self.cache_key {
+            std::fs::write(jar::with_suffix(Path::new(output), ".cache-key"), key)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates an empty directory named after `prefix`, the process and a counter below `OUT_DIR`, or
+/// the system's temporary directory outside of build scripts.
+fn unique_scratch_dir(prefix: &str) -> Result<ScratchDir, Error> {
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+    let base = std::env::var_os("OUT_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    Ok(ScratchDir::new(base.join(format!(
+        "{}-{}-{}",
+        prefix,
+        std::process::id(),
+        DIRS.fetch_add(1, Ordering::Relaxed)
+    )))?)
+}
+
+/// Passes every class file below `dir` through `transform`, writing back the returned bytes.
+fn transform_classes(dir: &Path, transform: &ClassTransform) -> Result<(), Error> {
+    for entry in jar::entries_of(dir)? {
+        let Some(name) = entry.strip_suffix(".class") else {
+            continue;
+        };
+        let path = dir.join(&entry);
+        let transformed = transform(name, std::fs::read(&path)?)?;
+        std::fs::write(&path, transformed)?;
+    }
+    Ok(())
+}
+
+/// Fails unless `output` is inside `out_dir`, after resolving both against the current directory.
+fn check_inside_out_dir(output: &str, out_dir: &Path) -> Result<(), Error> {
+    let out_dir = fs_helpers::normalize(&std::path::absolute(out_dir)?);
+    let absolute = fs_helpers::normalize(&std::path::absolute(output)?);
+    if !absolute.starts_with(&out_dir) {
+        return Err(Error::new(
+            ErrorKind::InvalidConfiguration,
+            format!(
+                "Output {} is outside of OUT_DIR {}, which `strict_out_dir` forbids",
+                output,
+                out_dir.display()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the path of the sources jar for `output`: `lib.jar` is accompanied by
+/// `lib-sources.jar`, and a `classes` directory by `classes-sources.jar`.
+fn sources_jar_path(output: &str) -> PathBuf {
+    let output = output.trim_end_matches(['/', '\\']);
+    let stem = output.strip_suffix(".jar").unwrap_or(output);
+    PathBuf::from(format!("{}-sources.jar", stem))
+}
+
+/// Returns whether `entry` is a jar named `<module_name>.jar` or `<module_name>-<version>.jar`.
+fn is_build_of_module(entry: &Path, module_name: &str) -> bool {
+    if entry.extension().is_none_or(|extension| extension != "jar") {
+        return false;
+    }
+
+    let stem = entry.file_stem().unwrap_or_default().to_string_lossy();
+    match stem.strip_prefix(module_name) {
+        Some("") => true,
+        Some(rest) => rest
+            .strip_prefix('-')
+            .is_some_and(|version| version.starts_with(|c: char| c.is_ascii_digit())),
+        None => false,
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    /// A Kotlin distribution whose kotlinc is a shell script reporting version `version`. Every
+    /// compile appends its arguments as a line to `invocations` and then runs `body`, which
+    /// finds the output passed through `-d` in `$out`.
+    pub(crate) struct FakeToolchain(ScratchDir);
+
+    impl FakeToolchain {
+        pub(crate) fn new(version: &str, body: &str) -> Self {
+            let dir = unique_scratch_dir("kotlin-test").unwrap();
+            std::fs::create_dir(dir.path().join("bin")).unwrap();
+            std::fs::create_dir(dir.path().join("lib")).unwrap();
+
+            let kotlinc = toolchain::compiler_in(dir.path());
+            let script = format!(
+                r#"#!/bin/sh
+if [ "$1" = "-version" ]; then
+    echo "info: kotlinc-jvm {version} (JRE 17.0.9+9)" >&2
+    exit 0
+fi
+echo "$*" >> "{invocations}"
+out=""
+prev=""
+for arg in "$@"; do
+    if [ "$prev" = "-d" ]; then out="$arg"; fi
+    prev="$arg"
+done
+{body}
+"#,
+                invocations = dir.path().join("invocations").display(),
+            );
+            std::fs::write(&kotlinc, script).unwrap();
+            std::fs::set_permissions(&kotlinc, std::fs::Permissions::from_mode(0o755)).unwrap();
+            Self(dir)
+        }
+
+        pub(crate) fn path(&self) -> &Path {
+            self.0.path()
+        }
+
+        /// Returns a quiet builder using this toolchain.
+        pub(crate) fn build(&self) -> Build {
+            let mut build = Build::new();
+            build.with_toolchain(self.path()).quiet();
+            build
+        }
+
+        /// Returns the arguments of every compile run so far, in order.
+        pub(crate) fn invocations(&self) -> Vec<String> {
+            std::fs::read_to_string(self.path().join("invocations"))
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_owned)
+                .collect()
+        }
+    }
+
+    /// Returns the arguments [`Build::compile`] would pass to kotlinc for `output`.
+    fn compile_args(build: &Build, output: &str) -> Vec<String> {
+        build
+            .command(output, build.backend)
+            .unwrap()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    /// Returns the first configuration problem [`Build::compile`] would stop at.
+    fn configuration_error(build: &Build) -> String {
+        build.command("out", build.backend).unwrap_err().to_string()
+    }
+
+    /// A script body that writes a class file to the output directory.
+    pub(crate) const WRITE_CLASS: &str =
+        r#"mkdir -p "$out" && printf 'class' > "$out/MainKt.class""#;
+
+    #[test]
+    fn after_compile_runs_with_the_output() {
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let output = toolchain.path().join("classes");
+        let marker = toolchain.path().join("marker");
+
+        let mut build = toolchain.build();
+        let touched = marker.clone();
+        build.after_compile(move |output| {
+            std::fs::write(&touched, output.to_string_lossy().as_bytes())?;
+            Ok(())
+        });
+        build.compile(&output.to_string_lossy()).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&marker).unwrap(),
+            output.to_string_lossy()
+        );
+    }
+
+    #[test]
+    fn after_compile_error_fails_the_compile() {
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let output = toolchain.path().join("classes");
+
+        let mut build = toolchain.build();
+        build.after_compile(|_| Err(Error::new(ErrorKind::IOError, "signing failed")));
+        let error = build.compile(&output.to_string_lossy()).unwrap_err();
+
+        assert_eq!(error.kind(), &ErrorKind::IOError);
+        assert!(error.to_string().contains("signing failed"));
+    }
+
+    #[test]
+    fn after_compile_is_skipped_when_kotlinc_fails() {
+        let toolchain = FakeToolchain::new("1.9.22", "exit 1");
+        let output = toolchain.path().join("classes");
+        let marker = toolchain.path().join("marker");
+
+        let mut build = toolchain.build();
+        let touched = marker.clone();
+        build.after_compile(move |_| Ok(std::fs::write(&touched, "")?));
+        assert!(build.compile(&output.to_string_lossy()).is_err());
+
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn compile_with_outputs_maps_sources_to_classes() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"printf 'output: Output:\n%s/MainKt.class\nSources:\n/src/Main.kt\n' "$out" >&2"#,
+        );
+        let output = toolchain.path().join("classes");
+
+        let outputs = toolchain
+            .build()
+            .compile_with_outputs(&output.to_string_lossy())
+            .unwrap();
+
+        assert_eq!(
+            outputs[Path::new("/src/Main.kt")],
+            [output.join("MainKt.class")]
+        );
+    }
+
+    #[test]
+    fn report_output_files_passes_the_flag() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build.report_output_files(true);
+
+        assert!(compile_args(&build, "out").contains(&"-Xreport-output-files".to_owned()));
+    }
+
+    #[test]
+    fn clear_files_and_classpath_keep_flags() {
+        let mut build = Build::new();
+        build
+            .java_home("/opt/jdk")
+            .no_stdlib(true)
+            .file("src/Main.kt")
+            .java_file("src/Util.java")
+            .source_root("src")
+            .classpath("lib/a.jar");
+
+        build.clear_files().clear_classpath();
+
+        assert!(build.get_files().is_empty());
+        assert!(build.get_java_files().is_empty());
+        assert!(build.get_source_roots().is_empty());
+        assert!(build.get_classpath().is_empty());
+        assert_eq!(build.get_java_home(), Some(Path::new("/opt/jdk")));
+        assert!(build.get_no_stdlib());
+    }
+
+    #[test]
+    fn disable_friend_modules_passes_the_flag() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build.disable_friend_modules(true);
+
+        assert!(compile_args(&build, "out").contains(&"-Xfriend-modules-disabled".to_owned()));
+    }
+
+    #[test]
+    fn disable_friend_modules_conflicts_with_friend_paths() {
+        let mut build = Build::new();
+        build.disable_friend_modules(true).friend_path("main.jar");
+
+        assert!(configuration_error(&build).contains("`disable_friend_modules`"));
+    }
+
+    #[test]
+    fn inherit_java_home_passes_java_home() {
+        let env = |name: &str| (name == "JAVA_HOME").then(|| OsString::from("/opt/inherited"));
+        let mut build = Build::new();
+        assert_eq!(
+            build.resolve_java_home_from(&env),
+            Some(PathBuf::from("/opt/inherited"))
+        );
+        assert_eq!(build.resolve_java_home_from(&|_| None), None);
+
+        build.inherit_java_home(false);
+        assert_eq!(build.resolve_java_home_from(&env), None);
+
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build.java_home("/opt/jdk");
+        let args = compile_args(&build, "out");
+        let java_home = args.iter().position(|arg| arg == "-java-home").unwrap();
+        assert_eq!(args[java_home + 1], "/opt/jdk");
+        assert_eq!(
+            build.resolve_java_home_from(&env),
+            Some(PathBuf::from("/opt/jdk"))
+        );
+    }
+
+    /// A script body that warns about two deprecated usages and writes a class file.
+    const WARN_DEPRECATED: &str = r#"
+echo "src/Main.kt:3:5: warning: 'legacyApi(): Unit' is deprecated. Use newApi instead" >&2
+echo "src/Main.kt:4:5: warning: 'oldHelper(): Unit' is deprecated. Scheduled for removal" >&2
+mkdir -p "$out" && printf 'class' > "$out/MainKt.class""#;
+
+    #[test]
+    fn fail_on_deprecation_rejects_listed_symbols() {
+        let toolchain = FakeToolchain::new("1.9.22", WARN_DEPRECATED);
+        let output = toolchain.path().join("classes");
+
+        let mut build = toolchain.build();
+        build.fail_on_deprecation("legacyApi");
+        let error = build.compile(&output.to_string_lossy()).unwrap_err();
+
+        assert_eq!(error.kind(), &ErrorKind::ToolExecError);
+        assert!(error.to_string().contains("`legacyApi`"));
+    }
+
+    #[test]
+    fn fail_on_deprecation_ignores_other_symbols() {
+        let toolchain = FakeToolchain::new("1.9.22", WARN_DEPRECATED);
+        let output = toolchain.path().join("classes");
+
+        let mut build = toolchain.build();
+        build.fail_on_deprecation("removedApi");
+        build.compile(&output.to_string_lossy()).unwrap();
+    }
+
+    #[test]
+    fn merge_appends_lists_and_overrides_changed_options() {
+        let mut base = Build::new();
+        base.file("src/Base.kt")
+            .classpath("lib/base.jar")
+            .java_home("/opt/jdk-17")
+            .module_name("base")
+            .no_reflect(true);
+
+        let mut overlay = Build::new();
+        overlay
+            .file("src/Overlay.kt")
+            .classpath("lib/overlay.jar")
+            .module_name("overlay")
+            .include_runtime(true);
+
+        base.merge(&overlay);
+
+        assert_eq!(
+            base.get_files(),
+            [
+                PathBuf::from("src/Base.kt"),
+                PathBuf::from("src/Overlay.kt")
+            ]
+        );
+        assert_eq!(
+            base.get_classpath(),
+            [
+                PathBuf::from("lib/base.jar"),
+                PathBuf::from("lib/overlay.jar")
+            ]
+        );
+        assert_eq!(base.get_module_name(), Some("overlay"));
+        assert_eq!(base.get_java_home(), Some(Path::new("/opt/jdk-17")));
+        assert!(base.get_no_reflect());
+        assert!(base.get_include_runtime());
+    }
+
+    #[test]
+    fn build_file_replaces_the_output_argument() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build.build_file("modules.xml");
+
+        let args = compile_args(&build, "out");
+        assert!(args.contains(&"-Xbuild-file=modules.xml".to_owned()));
+        assert!(!args.contains(&"-d".to_owned()));
+    }
+
+    #[test]
+    fn build_file_conflicts_with_sources_and_classpath() {
+        let mut build = Build::new();
+        build.build_file("modules.xml").file("src/Main.kt");
+        assert!(configuration_error(&build).contains("`build_file`"));
+
+        let mut build = Build::new();
+        build.build_file("modules.xml").classpath("lib/a.jar");
+        assert!(configuration_error(&build).contains("`build_file`"));
+    }
+
+    #[test]
+    fn compile_with_diagnostics_returns_warnings_on_success() {
+        let toolchain = FakeToolchain::new("1.9.22", WARN_DEPRECATED);
+        let output = toolchain.path().join("classes");
+
+        let diagnostics = toolchain
+            .build()
+            .compile_with_diagnostics(&output.to_string_lossy())
+            .unwrap();
+
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(
+            diagnostics[0].file.as_deref(),
+            Some(Path::new("src/Main.kt"))
+        );
+        assert_eq!(
+            (diagnostics[0].line, diagnostics[0].column),
+            (Some(3), Some(5))
+        );
+        assert!(output.join("MainKt.class").exists());
+    }
+
+    #[test]
+    fn test_build_makes_the_main_output_a_friend() {
+        let build = Build::test_build(Path::new("target/main.jar"));
+
+        assert_eq!(build.get_classpath(), [PathBuf::from("target/main.jar")]);
+        assert_eq!(build.get_friend_paths(), [PathBuf::from("target/main.jar")]);
+    }
+
+    #[test]
+    fn test_build_passes_friend_paths() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = Build::test_build(Path::new("target/main.jar"));
+        build.with_toolchain(toolchain.path()).quiet();
+
+        let args = compile_args(&build, "out");
+        assert!(args.contains(&"-Xfriend-paths=target/main.jar".to_owned()));
+        let classpath = args.iter().position(|arg| arg == "-cp").unwrap();
+        assert_eq!(args[classpath + 1], "target/main.jar");
+    }
+
+    /// A script body that packages two classes into the output jar, with timestamps that differ
+    /// between compiles.
+    const WRITE_JAR: &str = r#"
+classes="$out.in"
+rm -rf "$classes"
+mkdir -p "$classes/com/example"
+printf 'main' > "$classes/com/example/MainKt.class"
+printf 'util' > "$classes/com/example/Util.class"
+compiles=$(wc -l < "$(dirname "$0")/../invocations")
+find "$classes" -exec touch -d "20$((10 + compiles))-01-01T00:00:00" {} +
+jar cf "$out" -C "$classes" .
+rm -rf "$classes""#;
+
+    /// Returns whether the JDK `jar` tool is available, which the jar outputs need.
+    fn has_jar() -> bool {
+        Command::new("jar").arg("--version").output().is_ok()
+    }
+
+    #[test]
+    fn reproducible_jars_are_identical() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_JAR);
+        let output = toolchain.path().join("out.jar");
+        let output = output.to_string_lossy();
+
+        let mut build = toolchain.build();
+        build.compile(&output).unwrap();
+        let first = std::fs::read(&*output).unwrap();
+        build.compile(&output).unwrap();
+        assert_ne!(std::fs::read(&*output).unwrap(), first);
+
+        build.reproducible(true);
+        build.compile(&output).unwrap();
+        let first = std::fs::read(&*output).unwrap();
+        build.compile(&output).unwrap();
+        assert_eq!(std::fs::read(&*output).unwrap(), first);
+    }
+
+    #[test]
+    fn extra_checks_picks_the_flag_of_the_compiler() {
+        for (version, flag) in [
+            ("2.0.0", Some("-Xuse-fir-extended-checkers")),
+            ("1.9.22", Some("-Xextended-compiler-checks")),
+            ("1.4.32", None),
+        ] {
+            let toolchain = FakeToolchain::new(version, "");
+            let mut build = toolchain.build();
+            build.extra_checks(true);
+
+            let args = compile_args(&build, "out");
+            let checks = args
+                .iter()
+                .find(|arg| arg.contains("extended"))
+                .map(String::as_str);
+            assert_eq!(checks, flag, "kotlinc {}", version);
+        }
+    }
+
+    #[test]
+    fn emit_checksum_writes_a_sidecar() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_JAR);
+        let output = toolchain.path().join("out.jar");
+
+        let mut build = toolchain.build();
+        build.emit_checksum(true);
+        build.compile(&output.to_string_lossy()).unwrap();
+
+        let digest = ChecksumAlgorithm::Sha256.hex_digest(&output).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(toolchain.path().join("out.jar.sha256")).unwrap(),
+            format!("{}  out.jar\n", digest)
+        );
+    }
+
+    #[test]
+    fn command_wrapper_precedes_the_compiler() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build.command_wrapper("nix", vec!["run".into(), "--".into()]);
+
+        let cmd = build.command("out", build.backend).unwrap();
+        let args = cmd.get_args().take(3).collect::<Vec<_>>();
+        assert_eq!(cmd.get_program(), "nix");
+        assert_eq!(
+            args,
+            [
+                OsStr::new("run"),
+                OsStr::new("--"),
+                toolchain::compiler_in(toolchain.path()).as_os_str()
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_command_wrapper_is_reported() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build.command_wrapper("kotlin-rs-missing-wrapper", vec![]);
+
+        let error = build.compile("out").unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ToolNotFound);
+        assert!(error.to_string().contains("kotlin-rs-missing-wrapper"));
+    }
+
+    #[test]
+    fn source_root_passes_its_files() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let root = toolchain.path().join("src");
+        std::fs::create_dir_all(root.join("com/example")).unwrap();
+        std::fs::write(root.join("com/example/Main.kt"), "package com.example\n").unwrap();
+        std::fs::write(root.join("com/example/notes.txt"), "").unwrap();
+
+        let mut build = toolchain.build();
+        build.source_root(&root).validate_packages(true);
+
+        let args = compile_args(&build, "out");
+        let main = root.join("com/example/Main.kt");
+        assert!(args.contains(&main.to_string_lossy().into_owned()));
+        assert!(!args.iter().any(|arg| arg.ends_with("notes.txt")));
+    }
+
+    #[test]
+    fn validate_packages_rejects_misplaced_files() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let root = toolchain.path().join("src");
+        std::fs::create_dir_all(root.join("com/example")).unwrap();
+        std::fs::write(root.join("com/example/Main.kt"), "package com.other\n").unwrap();
+
+        let mut build = toolchain.build();
+        build.source_root(&root);
+        compile_args(&build, "out");
+
+        build.validate_packages(true);
+        let error = build.command("out", build.backend).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::InvalidConfiguration);
+        assert!(error
+            .to_string()
+            .contains("Main.kt declares package `com.other`"));
+    }
+
+    #[test]
+    fn quiet_disables_all_output() {
+        let mut build = Build::new();
+        build.debug(true).quiet();
+
+        assert!(!build.cargo_output.metadata);
+        assert!(!build.cargo_output.warnings);
+        assert!(!build.cargo_output.debug);
+        assert!(matches!(build.cargo_output.output, OutputKind::Discard));
+    }
+
+    /// A script body that fails when compiling with K2 through `-Xuse-k2`.
+    const FAIL_WITH_K2: &str = r#"
+case " $* " in
+    *" -Xuse-k2 "*)
+        echo "src/Main.kt:1:1: error: unresolved reference: k2" >&2
+        exit 1
+        ;;
+esac
+echo "src/Main.kt:2:1: warning: variable 'x' is never used" >&2
+mkdir -p "$out" && printf 'class' > "$out/MainKt.class""#;
+
+    #[test]
+    fn fallback_to_k1_retries_a_failed_k2_compile() {
+        let toolchain = FakeToolchain::new("1.8.0", FAIL_WITH_K2);
+        let output = toolchain.path().join("classes");
+
+        let mut build = toolchain.build();
+        build.backend(Backend::K2);
+        assert!(build.compile(&output.to_string_lossy()).is_err());
+        assert_eq!(toolchain.invocations().len(), 1);
+
+        build.fallback_to_k1(true);
+        build.compile(&output.to_string_lossy()).unwrap();
+
+        let invocations = toolchain.invocations();
+        assert_eq!(invocations.len(), 3);
+        assert!(invocations[1].contains("-Xuse-k2"));
+        assert!(!invocations[2].contains("-Xuse-k2"));
+    }
+
+    #[test]
+    fn fallback_to_k1_ignores_failed_policy_checks() {
+        let toolchain = FakeToolchain::new("1.8.0", WARN_DEPRECATED);
+        let output = toolchain.path().join("classes");
+
+        let mut build = toolchain.build();
+        build
+            .backend(Backend::K2)
+            .fallback_to_k1(true)
+            .max_warnings(0);
+        let error = build.compile(&output.to_string_lossy()).unwrap_err();
+
+        assert!(error.to_string().contains("`max_warnings`"));
+        assert_eq!(toolchain.invocations().len(), 1);
+    }
+
+    #[test]
+    fn getters_read_back_the_configuration() {
+        let mut build = Build::new();
+        assert!(build.get_files().is_empty());
+        assert_eq!(build.get_java_home(), None);
+        assert!(!build.get_include_runtime());
+        assert!(build.get_inherit_java_home());
+
+        build
+            .file("src/Main.kt")
+            .classpath("lib/a.jar")
+            .java_home("/opt/jdk")
+            .include_runtime(true)
+            .no_jdk(true)
+            .inherit_java_home(false)
+            .backend(Backend::K2)
+            .max_warnings(3);
+
+        assert_eq!(build.get_files(), [PathBuf::from("src/Main.kt")]);
+        assert_eq!(build.get_classpath(), [PathBuf::from("lib/a.jar")]);
+        assert_eq!(build.get_java_home(), Some(Path::new("/opt/jdk")));
+        assert!(build.get_include_runtime());
+        assert!(build.get_no_jdk());
+        assert!(!build.get_inherit_java_home());
+        assert_eq!(build.get_backend(), Backend::K2);
+        assert_eq!(build.get_max_warnings(), Some(3));
+    }
+
+    #[test]
+    fn compile_and_run_runs_the_main_class() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"jar cfe "$out" Hello -C "$(dirname "$0")/../hello" ."#,
+        );
+        let hello = toolchain.path().join("hello");
+        std::fs::create_dir(&hello).unwrap();
+        std::fs::write(
+            hello.join("Hello.java"),
+            "public class Hello { public static void main(String[] args) { System.exit(40 + args.length); } }",
+        )
+        .unwrap();
+        let javac = Command::new("javac")
+            .arg("-d")
+            .arg(&hello)
+            .arg(hello.join("Hello.java"))
+            .status();
+        if !javac.is_ok_and(|status| status.success()) {
+            return;
+        }
+        std::fs::remove_file(hello.join("Hello.java")).unwrap();
+
+        let output = toolchain.path().join("hello.jar");
+        let status = toolchain
+            .build()
+            .compile_and_run(&output.to_string_lossy(), &["a", "b"])
+            .unwrap();
+        assert_eq!(status.code(), Some(42));
+    }
+
+    #[test]
+    fn compile_and_run_requires_a_jar() {
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let output = toolchain.path().join("classes");
+
+        let error = toolchain
+            .build()
+            .compile_and_run(&output.to_string_lossy(), &[])
+            .unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::InvalidConfiguration);
+        assert!(toolchain.invocations().is_empty());
+    }
+
+    #[test]
+    fn compile_and_run_requires_a_main_class() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_JAR);
+        let output = toolchain.path().join("out.jar");
+
+        let error = toolchain
+            .build()
+            .compile_and_run(&output.to_string_lossy(), &[])
+            .unwrap_err();
+        assert!(error.to_string().contains("declares no Main-Class"));
+    }
+
+    #[test]
+    fn skip_prerelease_check_passes_the_flag() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        assert!(!compile_args(&build, "out").contains(&"-Xskip-prerelease-check".to_owned()));
+
+        build.skip_prerelease_check(true);
+        assert!(compile_args(&build, "out").contains(&"-Xskip-prerelease-check".to_owned()));
+    }
+
+    #[test]
+    fn append_to_jar_keeps_existing_entries() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_JAR);
+        let output = toolchain.path().join("out.jar");
+        let previous = toolchain.path().join("previous");
+        std::fs::create_dir_all(previous.join("com/example")).unwrap();
+        std::fs::write(previous.join("Old.class"), "old").unwrap();
+        std::fs::write(previous.join("com/example/Util.class"), "stale").unwrap();
+        let jar = Command::new("jar")
+            .arg("cf")
+            .arg(&output)
+            .arg("-C")
+            .arg(&previous)
+            .arg(".")
+            .status()
+            .unwrap();
+        assert!(jar.success());
+
+        let mut build = toolchain.build();
+        build.append_to_jar(true);
+        build.compile(&output.to_string_lossy()).unwrap();
+
+        assert_eq!(
+            build.output_entries(&output).unwrap(),
+            [
+                "META-INF/MANIFEST.MF",
+                "Old.class",
+                "com/example/MainKt.class",
+                "com/example/Util.class"
+            ]
+        );
+        let extracted = toolchain.path().join("extracted");
+        std::fs::create_dir(&extracted).unwrap();
+        build.jar_tool(None).extract(&output, &extracted).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(extracted.join("com/example/Util.class")).unwrap(),
+            "util"
+        );
+    }
+
+    #[test]
+    fn command_line_quotes_paths_with_spaces() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build.file("my sources/Main.kt").inherit_java_home(false);
+
+        let command_line = build.command_line("out dir").unwrap();
+        assert!(command_line.ends_with(" 'my sources/Main.kt' -d 'out dir'"));
+    }
+
+    #[test]
+    fn failed_compiles_report_the_command_line() {
+        let toolchain = FakeToolchain::new("1.9.22", "exit 3");
+        let mut build = toolchain.build();
+        build.file("my sources/Main.kt");
+
+        let output = toolchain.path().join("classes");
+        let error = build.compile(&output.to_string_lossy()).unwrap_err();
+        assert!(error.to_string().contains("'my sources/Main.kt'"));
+        assert_eq!(
+            build.last_command().unwrap(),
+            build.command_line(&output.to_string_lossy()).unwrap()
+        );
+    }
+
+    #[test]
+    fn render_diagnostic_names_passes_the_flag() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build.render_diagnostic_names(true);
+
+        assert!(
+            compile_args(&build, "out").contains(&"-Xrender-internal-diagnostic-names".to_owned())
+        );
+    }
+
+    #[test]
+    fn diagnostics_printed_to_stdout_are_captured() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"echo "src/Main.kt:7:3: error: unresolved reference: foo"
+exit 1"#,
+        );
+        let output = toolchain.path().join("classes");
+
+        let diagnostics = toolchain
+            .build()
+            .compile_with_diagnostics(&output.to_string_lossy())
+            .unwrap();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].message, "unresolved reference: foo");
+    }
+
+    #[test]
+    fn classpath_from_file_reads_a_lockfile() {
+        let dir = unique_scratch_dir("kotlin-test").unwrap();
+        std::fs::create_dir(dir.path().join("libs")).unwrap();
+        std::fs::write(dir.path().join("libs/a.jar"), "").unwrap();
+        std::fs::write(dir.path().join("libs/b.jar"), "").unwrap();
+        let lockfile = dir.path().join("classpath.lock");
+        std::fs::write(&lockfile, "# resolved\nlibs/a.jar\n\n  libs/b.jar  \n").unwrap();
+
+        let mut build = Build::new();
+        build.quiet().classpath_from_file(&lockfile).unwrap();
+        assert_eq!(
+            build.get_classpath(),
+            [dir.path().join("libs/a.jar"), dir.path().join("libs/b.jar")]
+        );
+    }
+
+    #[test]
+    fn classpath_from_file_rejects_missing_entries() {
+        let dir = unique_scratch_dir("kotlin-test").unwrap();
+        std::fs::write(dir.path().join("a.jar"), "").unwrap();
+        let lockfile = dir.path().join("classpath.lock");
+        std::fs::write(&lockfile, "a.jar\nmissing.jar\n").unwrap();
+
+        let mut build = Build::new();
+        let Err(error) = build.quiet().classpath_from_file(&lockfile) else {
+            panic!("missing.jar was accepted");
+        };
+        assert_eq!(error.kind(), &ErrorKind::InvalidConfiguration);
+        assert!(error.to_string().contains("missing.jar"));
+        assert!(build.get_classpath().is_empty());
+    }
+
+    #[test]
+    fn compile_checked_returns_the_artifact_and_warnings() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            &format!("echo 'info: kotlinc started' >&2\n{}", WARN_DEPRECATED),
+        );
+        let output = toolchain.path().join("classes");
+
+        let outcome = toolchain
+            .build()
+            .compile_checked(&output.to_string_lossy())
+            .unwrap();
+
+        assert_eq!(outcome.artifact, output);
+        assert!(outcome.artifact.join("MainKt.class").exists());
+        assert_eq!(outcome.warnings.len(), 2);
+        assert!(outcome
+            .warnings
+            .iter()
+            .all(|warning| warning.severity == Severity::Warning));
+    }
+
+    #[test]
+    fn raw_args_surround_the_source_files() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build
+            .file("Main.kt")
+            .file("Util.kt")
+            .raw_args_before_files(vec!["-Xbefore".into()])
+            .raw_args_after_files(vec!["-Xafter".into(), "value".into()]);
+
+        let args = compile_args(&build, "out");
+        let main = args.iter().position(|arg| arg == "Main.kt").unwrap();
+        assert_eq!(
+            args[main - 1..main + 4],
+            ["-Xbefore", "Main.kt", "Util.kt", "-Xafter", "value"]
+        );
+    }
+
+    /// Creates a jar at `jar` holding `entries`, each with its name as contents.
+    fn write_jar(jar: &Path, entries: &[&str]) {
+        let contents = jar::with_suffix(jar, ".in");
+        for entry in entries {
+            let file = contents.join(entry);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, entry).unwrap();
+        }
+        let status = Command::new("jar")
+            .arg("cf")
+            .arg(jar)
+            .arg("-C")
+            .arg(&contents)
+            .arg(".")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        std::fs::remove_dir_all(contents).unwrap();
+    }
+
+    #[test]
+    fn jar_from_classes_packages_a_compiled_directory() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let classes = toolchain.path().join("classes");
+        let jar = toolchain.path().join("out.jar");
+        write_jar(
+            &toolchain.path().join("lib/kotlin-stdlib.jar"),
+            &["kotlin/Unit.class"],
+        );
+
+        let build = toolchain.build();
+        build.compile(&classes.to_string_lossy()).unwrap();
+
+        build.jar_from_classes(&classes, &jar, false).unwrap();
+        assert_eq!(build.output_entries(&jar).unwrap(), ["MainKt.class"]);
+
+        build.jar_from_classes(&classes, &jar, true).unwrap();
+        assert_eq!(
+            build.output_entries(&jar).unwrap(),
+            ["MainKt.class", "kotlin/Unit.class"]
+        );
+    }
+
+    #[test]
+    fn experimental_flags_are_passed_once() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build
+            .experimental_flag(ExperimentalFlag::NoParamAssertions)
+            .experimental_flag(ExperimentalFlag::AllowResultReturnType)
+            .experimental_flag(ExperimentalFlag::NoParamAssertions);
+
+        let args = compile_args(&build, "out");
+        let flags = args
+            .iter()
+            .filter(|arg| arg.starts_with("-Xno-param") || arg.starts_with("-Xallow-result"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            flags,
+            ["-Xno-param-assertions", "-Xallow-result-return-type"]
+        );
+    }
+
+    #[test]
+    fn experimental_flags_are_checked_against_the_compiler() {
+        let toolchain = FakeToolchain::new("1.6.0", "");
+        let mut build = toolchain.build();
+        build.experimental_flag(ExperimentalFlag::ContextReceivers);
+
+        let error = build.command("out", build.backend).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "InvalidConfiguration: kotlinc 1.6.0 does not support -Xcontext-receivers, which \
+             requires kotlinc 1.6.20"
+        );
+    }
+
+    #[test]
+    fn on_tool_not_found_extends_the_error() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        std::fs::remove_file(toolchain::compiler_in(toolchain.path())).unwrap();
+        let output = toolchain.path().join("classes");
+
+        let mut build = toolchain.build();
+        let error = build.compile(&output.to_string_lossy()).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ToolNotFound);
+        assert!(!error.to_string().contains("internal wiki"));
+
+        build.on_tool_not_found(|| "Install kotlinc as described on the internal wiki".into());
+        let error = build.compile(&output.to_string_lossy()).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ToolNotFound);
+        assert!(error
+            .to_string()
+            .ends_with("\nInstall kotlinc as described on the internal wiki"));
+    }
+
+    #[test]
+    fn android_jar_replaces_the_jdk() {
+        let dir = unique_scratch_dir("kotlin-test").unwrap();
+        let android_jar = dir.path().join("android.jar");
+
+        let mut build = Build::new();
+        assert!(build.android_jar(&android_jar).is_err());
+        assert!(!build.get_no_jdk());
+
+        std::fs::write(&android_jar, "").unwrap();
+        build.android_jar(&android_jar).unwrap();
+        assert_eq!(build.get_classpath(), [android_jar]);
+        assert!(build.get_no_jdk());
+    }
+
+    #[test]
+    fn max_warnings_fails_compiles_over_the_limit() {
+        let toolchain = FakeToolchain::new("1.9.22", WARN_DEPRECATED);
+        let output = toolchain.path().join("classes");
+
+        let mut build = toolchain.build();
+        build.max_warnings(2);
+        build.compile(&output.to_string_lossy()).unwrap();
+
+        build.max_warnings(1);
+        let error = build.compile(&output.to_string_lossy()).unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ToolExecError);
+        assert!(error
+            .to_string()
+            .contains("reported 2 warnings, more than the 1 allowed"));
+    }
+
+    #[test]
+    fn relativize_diagnostics_to_shortens_paths() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"home=$(cd "$(dirname "$0")/.." && pwd)
+echo "$home/src/Main.kt:1:5: warning: variable 'x' is never used" >&2
+mkdir -p "$out""#,
+        );
+        let output = toolchain.path().join("classes");
+        let mut build = toolchain.build();
+        build.relativize_diagnostics_to(toolchain.path());
+
+        let diagnostics = build
+            .compile_with_diagnostics(&output.to_string_lossy())
+            .unwrap();
+        assert_eq!(
+            diagnostics[0].file.as_deref(),
+            Some(Path::new("src/Main.kt"))
+        );
+
+        let mut streamed = vec![];
+        build
+            .compile_to_writer(&output.to_string_lossy(), &mut streamed)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(streamed).unwrap(),
+            "src/Main.kt:1:5: warning: variable 'x' is never used\n"
+        );
+    }
+
+    #[test]
+    fn probe_flag_detects_unsupported_flags() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"case "$1" in
+    -Xbogus*) echo "warning: flag is not supported by this version of the compiler: $1" >&2 ;;
+esac"#,
+        );
+        let build = toolchain.build();
+
+        assert!(build.probe_flag("-Xno-optimize").unwrap());
+        assert!(!build.probe_flag("-Xbogus-flag").unwrap());
+        assert!(!build.probe_flag("-Xbogus-flag").unwrap());
+        assert_eq!(toolchain.invocations().len(), 2);
+    }
+
+    #[test]
+    fn profile_reports_and_dumps_phase_times() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+
+        build.profile(None);
+        let args = compile_args(&build, "out");
+        assert!(args.contains(&"-Xreport-perf".to_owned()));
+        assert!(!args.iter().any(|arg| arg.starts_with("-Xdump-perf")));
+
+        build.profile(Some("perf.txt".into()));
+        let args = compile_args(&build, "out");
+        assert!(args.contains(&"-Xreport-perf".to_owned()));
+        assert!(args.contains(&"-Xdump-perf=perf.txt".to_owned()));
+    }
+
+    #[test]
+    fn jar_compression_applies_to_rewritten_jars() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"mkdir -p "$out.in"
+head -c 100000 /dev/zero | tr '\0' a > "$out.in/Large.class"
+jar cf "$out" -C "$out.in" .
+rm -rf "$out.in""#,
+        );
+        let output = toolchain.path().join("out.jar");
+        let mut build = toolchain.build();
+        build.reproducible(true);
+
+        let mut size = |compression| {
+            build.jar_compression(compression);
+            build.compile(&output.to_string_lossy()).unwrap();
+            std::fs::metadata(&output).unwrap().len()
+        };
+        let stored = size(JarCompression::Stored);
+        let deflated = size(JarCompression::Deflated);
+
+        assert!(stored > 100000);
+        assert!(deflated < stored / 10);
+    }
+
+    #[test]
+    fn classpath_order_applies_to_the_classpath_argument() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build
+            .classpath("a.jar")
+            .classpath("kotlin-stdlib.jar")
+            .classpath_order(ClasspathOrder::StdlibFirst);
+
+        let args = compile_args(&build, "out");
+        let classpath = args.iter().position(|arg| arg == "-cp").unwrap();
+        let expected = std::env::join_paths(["kotlin-stdlib.jar", "a.jar"]).unwrap();
+        assert_eq!(args[classpath + 1], expected.to_string_lossy());
+    }
+
+    #[test]
+    fn filter_diagnostics_hides_lines_but_keeps_them_parsed() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"echo "build/generated/Api.kt:1:1: warning: unchecked cast" >&2
+echo "src/Main.kt:2:1: warning: variable 'x' is never used" >&2
+mkdir -p "$out""#,
+        );
+        let output = toolchain.path().join("classes");
+        let mut build = toolchain.build();
+        build.filter_diagnostics(|line| !line.contains("generated/"));
+
+        let mut forwarded = vec![];
+        build
+            .compile_to_writer(&output.to_string_lossy(), &mut forwarded)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(forwarded).unwrap(),
+            "src/Main.kt:2:1: warning: variable 'x' is never used\n"
+        );
+
+        let diagnostics = build
+            .compile_with_diagnostics(&output.to_string_lossy())
+            .unwrap();
+        assert_eq!(diagnostics.len(), 2);
+    }
+
+    #[test]
+    fn allow_empty_sources_compiles_without_sources() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"case " $* " in
+    *" -Xallow-no-source-files "*) mkdir -p "$out" ;;
+    *) echo "error: no source files" >&2; exit 1 ;;
+esac"#,
+        );
+        let output = toolchain.path().join("classes");
+        let mut build = toolchain.build();
+        assert!(build.compile(&output.to_string_lossy()).is_err());
+
+        build.allow_empty_sources(true);
+        build.compile(&output.to_string_lossy()).unwrap();
+        assert!(build.list_classes(&output).unwrap().is_empty());
+    }
+
+    #[test]
+    fn log_output_to_records_every_line() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"echo "src/Main.kt:2:1: warning: variable 'x' is never used" >&2
+echo "info: done" >&2
+mkdir -p "$out""#,
+        );
+        let output = toolchain.path().join("classes");
+        let log = toolchain.path().join("kotlinc.log");
+        std::fs::write(&log, "previous compile\n").unwrap();
+
+        let mut build = toolchain.build();
+        build
+            .log_output_to(&log)
+            .filter_diagnostics(|line| !line.starts_with("info"));
+        build.compile(&output.to_string_lossy()).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "src/Main.kt:2:1: warning: variable 'x' is never used\ninfo: done\n"
+        );
+    }
+
+    #[test]
+    fn optimize_false_passes_no_optimize() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        assert!(!compile_args(&build, "out").contains(&"-Xno-optimize".to_owned()));
+
+        build.optimize(false);
+        assert!(compile_args(&build, "out").contains(&"-Xno-optimize".to_owned()));
+    }
+
+    #[test]
+    fn compile_to_writer_writes_compiler_output() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"echo "src/Main.kt:2:1: warning: variable 'x' is never used" >&2
+echo "warning: classpath entry points to a non-existent location" >&2
+mkdir -p "$out""#,
+        );
+        let output = toolchain.path().join("classes");
+
+        let mut sink = vec![];
+        let artifact = toolchain
+            .build()
+            .compile_to_writer(&output.to_string_lossy(), &mut sink)
+            .unwrap();
+
+        assert_eq!(artifact, output);
+        assert_eq!(
+            String::from_utf8(sink).unwrap(),
+            "src/Main.kt:2:1: warning: variable 'x' is never used\n\
+             warning: classpath entry points to a non-existent location\n"
+        );
+    }
+
+    #[test]
+    fn runtime_variant_selects_the_bundled_jars() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let lib = toolchain.path().join("lib");
+        write_jar(&lib.join("kotlin-stdlib.jar"), &["kotlin/Unit.class"]);
+        write_jar(
+            &lib.join("kotlin-stdlib-jdk7.jar"),
+            &["kotlin/jdk7/AutoCloseableKt.class"],
+        );
+        write_jar(
+            &lib.join("kotlin-stdlib-jdk8.jar"),
+            &["kotlin/streams/jdk8/StreamsKt.class"],
+        );
+        let classes = toolchain.path().join("classes");
+        let jar = toolchain.path().join("out.jar");
+
+        let mut build = toolchain.build();
+        build.compile(&classes.to_string_lossy()).unwrap();
+
+        build.jar_from_classes(&classes, &jar, true).unwrap();
+        assert_eq!(
+            build.output_entries(&jar).unwrap(),
+            [
+                "MainKt.class",
+                "kotlin/Unit.class",
+                "kotlin/jdk7/AutoCloseableKt.class",
+                "kotlin/streams/jdk8/StreamsKt.class"
+            ]
+        );
+
+        build.runtime_variant(RuntimeVariant::Minimal);
+        build.jar_from_classes(&classes, &jar, true).unwrap();
+        assert_eq!(
+            build.output_entries(&jar).unwrap(),
+            ["MainKt.class", "kotlin/Unit.class"]
+        );
+    }
+
+    #[test]
+    fn last_command_records_the_latest_compile() {
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let first = toolchain.path().join("first");
+        let second = toolchain.path().join("second");
+        let build = toolchain.build();
+        assert_eq!(build.last_command(), None);
+
+        build.compile(&first.to_string_lossy()).unwrap();
+        build.compile(&second.to_string_lossy()).unwrap();
+
+        let last_command = build.last_command().unwrap();
+        assert!(
+            last_command.starts_with(&*toolchain::compiler_in(toolchain.path()).to_string_lossy())
+        );
+        assert!(last_command.ends_with(&format!("-d {}", second.display())));
+    }
+
+    #[test]
+    fn extended_checkers_passes_the_flag() {
+        let toolchain = FakeToolchain::new("2.0.0", "");
+        let mut build = toolchain.build();
+        build.extended_checkers(true).extra_checks(true);
+
+        let args = compile_args(&build, "out");
+        let checkers = args
+            .iter()
+            .filter(|arg| *arg == "-Xuse-fir-extended-checkers")
+            .count();
+        assert_eq!(checkers, 1);
+    }
+
+    #[test]
+    fn extended_checkers_conflict_with_k1() {
+        let mut build = Build::new();
+        build.extended_checkers(true).backend(Backend::K1);
+        assert!(configuration_error(&build).contains("`extended_checkers`"));
+
+        let mut build = Build::new();
+        build.extra_checks(true).backend(Backend::K1);
+        assert!(configuration_error(&build).contains("`extra_checks`"));
+    }
+
+    #[test]
+    fn k1_retries_leave_out_the_extended_checkers() {
+        let toolchain = FakeToolchain::new("2.0.0", "");
+        let mut build = toolchain.build();
+        build.backend(Backend::K2).extended_checkers(true);
+        let retry = build.command("out", Backend::K1).unwrap();
+        assert!(!retry
+            .get_args()
+            .any(|arg| arg == "-Xuse-fir-extended-checkers"));
+
+        let mut build = toolchain.build();
+        build.backend(Backend::K2).extra_checks(true);
+        let retry = build.command("out", Backend::K1).unwrap();
+        assert!(!retry
+            .get_args()
+            .any(|arg| arg == "-Xuse-fir-extended-checkers"));
+    }
+
+    #[test]
+    fn validate_reports_every_problem() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let missing = toolchain.path().join("missing");
+        let mut build = Build::new();
+        build
+            .with_toolchain(missing.join("kotlinc"))
+            .file(missing.join("Main.kt"))
+            .friend_path(toolchain.path())
+            .disable_friend_modules(true);
+
+        let problems = build
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        let reported = |needle: &str| problems.iter().any(|problem| problem.contains(needle));
+        assert!(reported("`disable_friend_modules`"), "{:?}", problems);
+        assert!(reported("Main.kt does not exist"), "{:?}", problems);
+        assert!(reported("kotlinc"), "{:?}", problems);
+        assert!(problems.len() >= 3, "{:?}", problems);
+
+        assert_eq!(toolchain.build().validate().map_err(|e| e.len()), Ok(()));
+    }
+
+    #[test]
+    fn string_concat_passes_each_mode() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        for (mode, flag) in [
+            (
+                StringConcatMode::IndyWithConstants,
+                "-Xstring-concat=indy-with-constants",
+            ),
+            (StringConcatMode::Indy, "-Xstring-concat=indy"),
+            (StringConcatMode::Inline, "-Xstring-concat=inline"),
+        ] {
+            let mut build = toolchain.build();
+            build.string_concat(mode);
+            assert!(compile_args(&build, "out").iter().any(|arg| arg == flag));
+        }
+    }
+
+    #[test]
+    fn string_concat_requires_kotlin_1_4_20() {
+        let toolchain = FakeToolchain::new("1.4.10", "");
+        let mut build = toolchain.build();
+        build.string_concat(StringConcatMode::Indy);
+
+        let error = build.command("out", build.backend).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("kotlinc 1.4.10 does not support -Xstring-concat=indy"));
+    }
+
+    #[test]
+    fn sources_jar_contains_the_sources() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_JAR);
+        let main = toolchain.path().join("Main.kt");
+        std::fs::write(&main, "package com.example\n\nfun main() {}\n").unwrap();
+        let root = toolchain.path().join("src");
+        std::fs::create_dir_all(root.join("com/example/util")).unwrap();
+        std::fs::write(
+            root.join("com/example/util/Util.kt"),
+            "package com.example.util\n",
+        )
+        .unwrap();
+        let output = toolchain.path().join("lib.jar");
+
+        let mut build = toolchain.build();
+        build.file(&main).source_root(&root).emit_sources_jar(true);
+        let (jar, sources_jar) = build
+            .compile_with_sources_jar(&output.to_string_lossy())
+            .unwrap();
+
+        assert_eq!(jar, output);
+        let sources_jar = sources_jar.unwrap();
+        assert_eq!(sources_jar, toolchain.path().join("lib-sources.jar"));
+        let entries = build.output_entries(&sources_jar).unwrap();
+        assert!(
+            entries.contains(&"com/example/Main.kt".to_owned()),
+            "{:?}",
+            entries
+        );
+        assert!(
+            entries.contains(&"com/example/util/Util.kt".to_owned()),
+            "{:?}",
+            entries
+        );
+    }
+
+    #[test]
+    fn sources_jar_path_follows_the_output() {
+        assert_eq!(sources_jar_path("lib.jar"), Path::new("lib-sources.jar"));
+        assert_eq!(
+            sources_jar_path("target/classes/"),
+            Path::new("target/classes-sources.jar")
+        );
+    }
+
+    #[test]
+    fn missing_compiler_hook_provides_kotlinc() {
+        if toolchain::find_compiler().is_some() {
+            return;
+        }
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let output = toolchain.path().join("out");
+        let compiler = toolchain::compiler_in(toolchain.path());
+
+        let mut build = Build::new();
+        build.quiet().on_missing_compiler(move || Ok(compiler));
+        build.compile(&output.to_string_lossy()).unwrap();
+
+        assert!(output.join("MainKt.class").is_file());
+        assert_eq!(toolchain.invocations().len(), 1);
+    }
+
+    #[test]
+    fn missing_compiler_hook_errors_fail_the_compile() {
+        if toolchain::find_compiler().is_some() {
+            return;
+        }
+        let mut build = Build::new();
+        build
+            .quiet()
+            .on_missing_compiler(|| Err(Error::new(ErrorKind::ToolNotFound, "download failed")));
+
+        let error = build.compile("out").unwrap_err();
+        assert_eq!(error.to_string(), "ToolNotFound: download failed");
+    }
+
+    #[test]
+    fn latest_stable_follows_the_installed_compiler() {
+        let toolchain = FakeToolchain::new("2.1.10", "");
+        let mut build = toolchain.build();
+        build
+            .language_version(KotlinVersion::LatestStable)
+            .api_version(KotlinVersion::Exact(1, 9));
+
+        let args = compile_args(&build, "out");
+        let flag = |flag: &str| {
+            let index = args.iter().position(|arg| arg == flag).unwrap();
+            args[index + 1].clone()
+        };
+        assert_eq!(flag("-language-version"), "2.1");
+        assert_eq!(flag("-api-version"), "1.9");
+        assert_eq!(build.compiler_version.get().map(|v| v.is_ok()), Some(true));
+    }
+
+    #[test]
+    fn classpath_jars_of_the_compiled_module_are_detected() {
+        assert!(is_build_of_module(Path::new("libs/app.jar"), "app"));
+        assert!(is_build_of_module(Path::new("libs/app-1.2.0.jar"), "app"));
+        assert!(!is_build_of_module(Path::new("libs/app-utils.jar"), "app"));
+        assert!(!is_build_of_module(
+            Path::new("libs/application.jar"),
+            "app"
+        ));
+        assert!(!is_build_of_module(Path::new("target/app"), "app"));
+    }
+
+    #[test]
+    fn classpath_overlap_warnings_can_be_disabled() {
+        let mut build = Build::new();
+        assert!(build.get_warn_classpath_overlap());
+        build.warn_classpath_overlap(false);
+        assert!(!build.get_warn_classpath_overlap());
+    }
+
+    #[test]
+    fn cache_key_changes_force_a_recompile() {
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let output = toolchain.path().join("out");
+        let output = output.to_string_lossy();
+
+        let mut build = toolchain.build();
+        build.cache_key("generator 1");
+        build.compile(&output).unwrap();
+        build.compile(&output).unwrap();
+        assert_eq!(toolchain.invocations().len(), 1);
+        assert_eq!(
+            std::fs::read_to_string(toolchain.path().join("out.cache-key")).unwrap(),
+            "generator 1"
+        );
+
+        build.cache_key("generator 2");
+        build.compile(&output).unwrap();
+        assert_eq!(toolchain.invocations().len(), 2);
+
+        std::fs::remove_dir_all(&*output).unwrap();
+        build.compile(&output).unwrap();
+        assert_eq!(toolchain.invocations().len(), 3);
+    }
+
+    #[test]
+    fn assertions_pass_each_mode() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        for (mode, flag) in [
+            (AssertionsMode::Jvm, "-Xassertions=jvm"),
+            (AssertionsMode::Always, "-Xassertions=always-enable"),
+            (AssertionsMode::Never, "-Xassertions=always-disable"),
+        ] {
+            let mut build = toolchain.build();
+            build.assertions(mode);
+            let args = compile_args(&build, "out");
+            assert!(args.iter().any(|arg| arg == flag), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn list_classes_of_a_classes_directory() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"
+mkdir -p "$out/META-INF" "$out/com/example/impl"
+printf '' > "$out/META-INF/main.kotlin_module"
+printf '' > "$out/com/example/MainKt.class"
+printf '' > "$out/com/example/impl/Util.class""#,
+        );
+        let output = toolchain.path().join("classes");
+        let build = toolchain.build();
+        build.compile(&output.to_string_lossy()).unwrap();
+
+        assert_eq!(
+            build.list_classes(&output).unwrap(),
+            [
+                PathBuf::from("com/example/MainKt.class"),
+                PathBuf::from("com/example/impl/Util.class")
+            ]
+        );
+    }
+
+    #[test]
+    fn list_classes_of_a_jar() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_JAR);
+        let output = toolchain.path().join("out.jar");
+        let build = toolchain.build();
+        build.compile(&output.to_string_lossy()).unwrap();
+
+        assert_eq!(
+            build.list_classes(&output).unwrap(),
+            [
+                PathBuf::from("com/example/MainKt.class"),
+                PathBuf::from("com/example/Util.class")
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_name_the_configured_compiler() {
+        let toolchain = FakeToolchain::new("1.9.22", "exit 3");
+        let compiler = toolchain::compiler_in(toolchain.path());
+        let error = toolchain.build().compile("out").unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ToolExecError);
+        assert!(
+            error.to_string().contains(&*compiler.to_string_lossy()),
+            "{}",
+            error
+        );
+
+        std::fs::remove_file(&compiler).unwrap();
+        let error = toolchain.build().compile("out").unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ToolNotFound);
+        assert!(
+            error
+                .to_string()
+                .contains(&format!("Is `{}` installed?", compiler.display())),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn source_encoding_is_passed_to_the_compiler_jvm() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build.source_encoding("windows-1252");
+        assert!(compile_args(&build, "out")
+            .iter()
+            .any(|arg| arg == "-J-Dfile.encoding=windows-1252"));
+
+        let mut build = Build::new();
+        build.source_encoding("klingon");
+        assert!(configuration_error(&build).contains("\"klingon\" is not a known charset"));
+    }
+
+    #[test]
+    fn java_files_are_compiled_with_the_kotlin_files() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let root = toolchain.path().join("src");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("Main.kt"), "").unwrap();
+        std::fs::write(root.join("Util.java"), "").unwrap();
+
+        let mut build = toolchain.build();
+        build
+            .file(root.join("Main.kt"))
+            .java_file(root.join("Util.java"));
+        let args = compile_args(&build, "out");
+        assert!(args.windows(2).any(|files| files
+            == [
+                root.join("Main.kt").to_string_lossy(),
+                root.join("Util.java").to_string_lossy(),
+            ]));
+
+        let mut build = toolchain.build();
+        build.source_root(&root);
+        let args = compile_args(&build, "out");
+        assert!(!args.iter().any(|arg| arg.ends_with("Util.java")));
+
+        build.include_java_sources(true);
+        let args = compile_args(&build, "out");
+        assert!(args.iter().any(|arg| arg.ends_with("Main.kt")));
+        assert!(args.iter().any(|arg| arg.ends_with("Util.java")));
+    }
+
+    #[test]
+    fn jspecify_annotations_pass_each_mode() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        for (mode, flag) in [
+            (NullabilityMode::Ignore, "-Xjspecify-annotations=ignore"),
+            (NullabilityMode::Warning, "-Xjspecify-annotations=warn"),
+            (NullabilityMode::Strict, "-Xjspecify-annotations=strict"),
+        ] {
+            let mut build = toolchain.build();
+            build.jspecify_annotations(mode);
+            let args = compile_args(&build, "out");
+            assert!(args.iter().any(|arg| arg == flag), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn debug_output_follows_the_toggle() {
+        let mut build = Build::new();
+        build.debug(false);
+        assert!(!build.cargo_output.debug);
+        build.debug(true);
+        assert!(build.cargo_output.debug);
+    }
+
+    #[test]
+    fn compile_temp_removes_the_output_on_drop() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_JAR);
+        let (dir, artifact) = toolchain.build().compile_temp().unwrap();
+        let path = dir.path().to_path_buf();
+        assert!(artifact.starts_with(&path));
+        assert!(artifact.is_file());
+
+        drop(dir);
+        assert!(!artifact.exists());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn dump_phases_are_joined_with_commas() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        assert!(!compile_args(&build, "out")
+            .iter()
+            .any(|arg| arg.starts_with("-Xphases-to-dump")));
+
+        build
+            .dump_phase("JvmInlineClassLowering")
+            .dump_phase("FunctionInlining");
+        let args = compile_args(&build, "out");
+        assert!(args
+            .iter()
+            .any(|arg| arg == "-Xphases-to-dump=JvmInlineClassLowering,FunctionInlining"));
+    }
+
+    #[test]
+    fn friend_of_adds_the_output_and_classpath() {
+        let mut main = Build::new();
+        main.classpath("libs/a.jar").classpath("libs/b.jar");
+
+        let mut test = Build::new();
+        test.classpath("libs/b.jar").classpath("libs/junit.jar");
+        test.friend_of(&main, Path::new("target/main.jar"));
+
+        assert_eq!(
+            test.get_classpath(),
+            [
+                "libs/b.jar",
+                "libs/junit.jar",
+                "target/main.jar",
+                "libs/a.jar"
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(test.get_friend_paths(), [PathBuf::from("target/main.jar")]);
+    }
+
+    #[test]
+    fn backend_threads_passes_the_count() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build.backend_threads(4);
+        assert!(compile_args(&build, "out")
+            .iter()
+            .any(|arg| arg == "-Xbackend-threads=4"));
+
+        build.backend_threads(0);
+        assert!(configuration_error(&build).contains("has to be at least 1"));
+
+        let toolchain = FakeToolchain::new("1.6.10", "");
+        let mut build = toolchain.build();
+        build.backend_threads(4);
+        assert!(build.command("out", build.backend).is_err());
+    }
+
+    #[test]
+    fn metadata_vars_are_printed_for_the_output() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let jar = toolchain.path().join("lib.jar");
+        std::fs::write(&jar, "abc").unwrap();
+        let jar = jar.to_string_lossy();
+
+        let mut build = toolchain.build();
+        build
+            .emit_metadata_var("jar", &jar)
+            .emit_metadata_var("version", "1.2.0");
+        assert_eq!(
+            build.output_metadata(&jar).unwrap(),
+            [
+                format!("cargo:jar={}", jar),
+                "cargo:version=1.2.0".to_owned()
+            ]
+        );
+
+        build.emit_checksum(true);
+        assert_eq!(
+            build.output_metadata(&jar).unwrap()[0],
+            "cargo:sha256=ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn strict_out_dir_rejects_outputs_outside_of_out_dir() {
+        let out_dir = Path::new("/work/target/debug/build/app-1234/out");
+        assert!(
+            check_inside_out_dir("/work/target/debug/build/app-1234/out/lib.jar", out_dir).is_ok()
+        );
+
+        let error =
+            check_inside_out_dir("/work/target/debug/build/app-1234/out/../lib.jar", out_dir)
+                .unwrap_err();
+        assert!(error.to_string().contains("is outside of OUT_DIR"));
+        assert!(check_inside_out_dir("/tmp/lib.jar", out_dir).is_err());
+
+        let mut build = Build::new();
+        assert!(build.check_out_dir("/tmp/lib.jar").is_ok());
+        build.strict_out_dir(true);
+        if std::env::var_os("CARGO").is_some() && std::env::var_os("OUT_DIR").is_none() {
+            let error = build.check_out_dir("/tmp/lib.jar").unwrap_err();
+            assert!(error.to_string().contains("requires OUT_DIR"));
+        }
+    }
+
+    #[test]
+    fn inference_flags_are_passed_once_and_version_gated() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build
+            .inference_flag(InferenceFlag::SelfUpperBound)
+            .inference_flag(InferenceFlag::NewInference)
+            .inference_flag(InferenceFlag::SelfUpperBound);
+        let args = compile_args(&build, "out");
+        let flags = args
+            .iter()
+            .filter(|arg| arg.ends_with("inference"))
+            .collect::<Vec<_>>();
+        assert_eq!(flags, ["-Xself-upper-bound-inference", "-Xnew-inference"]);
+
+        let toolchain = FakeToolchain::new("1.5.32", "");
+        let mut build = toolchain.build();
+        build.inference_flag(InferenceFlag::SelfUpperBound);
+        assert!(build.command("out", build.backend).is_err());
+    }
+
+    #[test]
+    fn diagnostics_iter_yields_the_diagnostics_of_a_failing_compile() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"
+echo "src/Main.kt:1:5: warning: parameter 'x' is never used" >&2
+echo "src/Main.kt:2:9: error: unresolved reference: foo" >&2
+echo "    foo()" >&2
+exit 1"#,
+        );
+        let mut diagnostics = toolchain.build().compile_diagnostics_iter("out").unwrap();
+
+        let warning = diagnostics.next().unwrap();
+        assert_eq!(warning.severity, Severity::Warning);
+        assert_eq!(warning.line, Some(1));
+        let error = diagnostics.next().unwrap();
+        assert_eq!(error.severity, Severity::Error);
+        assert_eq!(error.message, "unresolved reference: foo");
+        assert_eq!(diagnostics.next(), None);
+
+        let error = diagnostics.finish().unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ToolExecError);
+    }
+
+    #[test]
+    fn runtime_stdlib_jar_replaces_the_toolchain_runtime() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let classes = toolchain.path().join("classes");
+        let jar = toolchain.path().join("out.jar");
+        let stdlib = toolchain.path().join("vendor/kotlin-stdlib-2.0.0.jar");
+        write_jar(&stdlib, &["kotlin/Pair.class"]);
+
+        let mut build = toolchain.build();
+        build.runtime_stdlib_jar(&stdlib);
+        build.compile(&classes.to_string_lossy()).unwrap();
+        build.jar_from_classes(&classes, &jar, true).unwrap();
+        assert_eq!(
+            build.output_entries(&jar).unwrap(),
+            ["MainKt.class", "kotlin/Pair.class"]
+        );
+
+        let not_stdlib = toolchain.path().join("vendor/other.jar");
+        write_jar(&not_stdlib, &["com/example/Other.class"]);
+        build.runtime_stdlib_jar(&not_stdlib);
+        let error = build.jar_from_classes(&classes, &jar, true).unwrap_err();
+        assert!(error.to_string().contains("contains no `kotlin/` entries"));
+
+        build.runtime_stdlib_jar(toolchain.path().join("vendor/missing.jar"));
+        let error = build.jar_from_classes(&classes, &jar, true).unwrap_err();
+        assert!(error.to_string().contains("does not exist"));
+    }
+
+    #[test]
+    fn expect_actual_classes_depends_on_the_compiler_version() {
+        let has_flag = |version: &str| {
+            let toolchain = FakeToolchain::new(version, "");
+            let mut build = toolchain.build();
+            build.expect_actual_classes(true);
+            compile_args(&build, "out")
+                .iter()
+                .any(|arg| arg == "-Xexpect-actual-classes")
+        };
+        assert!(has_flag("1.9.20"));
+        assert!(has_flag("2.0.0"));
+        assert!(!has_flag("1.9.10"));
+    }
+
+    #[test]
+    fn compile_snippet_returns_the_class_bytes() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"
+mkdir -p "$out/com/example"
+printf '\312\376\272\276\000\000\000\075' > "$out/com/example/Foo.class""#,
+        );
+        let build = toolchain.build();
+        let bytes = build
+            .compile_snippet("com.example.Foo", "package com.example\nclass Foo\n")
+            .unwrap();
+        assert_eq!(bytes[..4], [0xca, 0xfe, 0xba, 0xbe]);
+
+        let invocation = toolchain.invocations().pop().unwrap();
+        let source = invocation
+            .split(' ')
+            .find(|arg| arg.ends_with("Snippet.kt"))
+            .unwrap();
+        assert!(!Path::new(source).exists());
+
+        let error = build.compile_snippet("com.example.Bar", "").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Failed to read class com.example.Bar"));
+    }
+
+    #[test]
+    fn fragments_assemble_their_flags() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build
+            .fragment("common")
+            .fragment("jvm")
+            .fragment_source("common", Path::new("src/common/Api.kt"))
+            .fragment_source("jvm", Path::new("src/jvm/Api.kt"))
+            .fragment_refines("jvm", "common");
+
+        let args = compile_args(&build, "out");
+        for flag in [
+            "-Xmulti-platform",
+            "-Xfragments=common,jvm",
+            "-Xfragment-sources=common:src/common/Api.kt,jvm:src/jvm/Api.kt",
+            "-Xfragment-refines=jvm:common",
+            "src/common/Api.kt",
+            "src/jvm/Api.kt",
+        ] {
+            assert!(args.iter().any(|arg| arg == flag), "{} in {:?}", flag, args);
+        }
+
+        build.fragment_refines("js", "common");
+        assert!(configuration_error(&build).contains("undeclared fragments: `js`"));
+    }
+
+    #[test]
+    fn compile_and_list_sorted_returns_a_stable_listing() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"
+mkdir -p "$out/META-INF" "$out/com/example"
+printf '' > "$out/com/example/Util.class"
+printf '' > "$out/META-INF/main.kotlin_module"
+printf '' > "$out/com/example/MainKt.class""#,
+        );
+        let output = toolchain.path().join("classes");
+        let listing = toolchain
+            .build()
+            .compile_and_list_sorted(&output.to_string_lossy())
+            .unwrap();
+        assert_eq!(
+            listing,
+            [
+                "META-INF/main.kotlin_module",
+                "com/example/MainKt.class",
+                "com/example/Util.class"
+            ]
+        );
+    }
+
+    #[test]
+    fn disable_scripting_plugin_passes_the_flag() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build.file("Main.kt").disable_scripting_plugin(true);
+        assert!(compile_args(&build, "out")
+            .iter()
+            .any(|arg| arg == "-Xdisable-default-scripting-plugin"));
+
+        build.file("build.kts");
+        assert!(configuration_error(&build).contains("cannot be combined with script"));
+    }
+
+    #[test]
+    fn from_cargo_dependencies_reads_the_dep_vars() {
+        let paths = std::env::join_paths(["/deps/util/a.jar", "/deps/util/b.jar"]).unwrap();
+        let vars = [
+            ("DEP_UTIL_TEST_CLASSPATH", paths),
+            ("DEP_CORE_TEST_CLASSPATH", "/deps/core/core.jar".into()),
+            ("DEP_CORE_TEST_CLASSPATH_DIR", "/deps/core/classes".into()),
+            ("CARGO_PKG_NAME", "app".into()),
+        ];
+
+        let build = Build::from_dependency_vars(
+            "test-classpath",
+            vars.map(|(name, value)| (name.into(), value)),
+        );
+
+        assert_eq!(
+            build.get_classpath(),
+            [
+                "/deps/core/core.jar",
+                "/deps/util/a.jar",
+                "/deps/util/b.jar"
+            ]
+            .map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn use_javac_passes_the_javac_flags() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build
+            .file("Main.kt")
+            .java_file("Util.java")
+            .use_javac(true)
+            .javac_arg("-parameters")
+            .javac_arg("-Xlint:all");
+
+        let args = compile_args(&build, "out");
+        for flag in [
+            "-Xuse-javac",
+            "-Xcompile-java",
+            "-Xjavac-arguments=-parameters,-Xlint:all",
+        ] {
+            assert!(args.iter().any(|arg| arg == flag), "{} in {:?}", flag, args);
+        }
+    }
+
+    #[test]
+    fn use_javac_requires_java_sources() {
+        let mut build = Build::new();
+        build.file("Main.kt").use_javac(true);
+        assert!(configuration_error(&build).contains("`use_javac` requires Java sources"));
+
+        let mut build = Build::new();
+        build.java_file("Util.java").javac_arg("-parameters");
+        assert!(configuration_error(&build).contains("`javac_arg` requires `use_javac`"));
+
+        let mut build = Build::new();
+        build
+            .java_file("Util.java")
+            .use_javac(true)
+            .javac_arg("-Aa=1,2");
+        assert!(configuration_error(&build).contains("cannot contain a comma"));
+    }
+
+    #[test]
+    fn transform_class_rewrites_directory_outputs() {
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let output = toolchain.path().join("classes");
+        let mut build = toolchain.build();
+        build.transform_class(|name, bytes| {
+            assert_eq!(name, "MainKt");
+            Ok(String::from_utf8(bytes)
+                .unwrap()
+                .replace("class", "patched")
+                .into())
+        });
+        build.compile(&output.to_string_lossy()).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(output.join("MainKt.class")).unwrap(),
+            "patched"
+        );
+    }
+
+    #[test]
+    fn transform_class_rewrites_jar_outputs() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_JAR);
+        let output = toolchain.path().join("out.jar");
+        let mut build = toolchain.build();
+        build.transform_class(|name, bytes| match name {
+            "com/example/Util" => Ok(b"patched".to_vec()),
+            _ => Ok(bytes),
+        });
+        build.compile(&output.to_string_lossy()).unwrap();
+
+        let extracted = toolchain.path().join("extracted");
+        std::fs::create_dir_all(&extracted).unwrap();
+        build.jar_tool(None).extract(&output, &extracted).unwrap();
+        let read = |class: &str| std::fs::read_to_string(extracted.join(class)).unwrap();
+        assert_eq!(read("com/example/Util.class"), "patched");
+        assert_eq!(read("com/example/MainKt.class"), "main");
+    }
+
+    #[test]
+    fn transform_class_errors_fail_the_compile() {
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let output = toolchain.path().join("classes");
+        let mut build = toolchain.build();
+        build.transform_class(|_, _| Err(Error::new(ErrorKind::IOError, "rewrite failed")));
+
+        let error = build.compile(&output.to_string_lossy()).unwrap_err();
+        assert!(error.to_string().contains("rewrite failed"));
+    }
+
+    #[test]
+    fn fail_fast_stops_at_the_first_error() {
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"
+echo "src/A.kt:1:1: warning: unused variable" >&2
+echo "src/A.kt:2:1: error: unresolved reference: foo" >&2
+sleep 30
+echo "src/A.kt:3:1: error: unresolved reference: bar" >&2
+touch "$(dirname "$0")/../finished""#,
+        );
+        let log = toolchain.path().join("kotlinc.log");
+        let mut build = toolchain.build();
+        build.fail_fast(true).log_output_to(&log);
+
+        let started = std::time::Instant::now();
+        let error = build.compile("out").unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(20));
+        assert_eq!(error.kind(), &ErrorKind::ToolExecError);
+
+        let log = std::fs::read_to_string(&log).unwrap();
+        assert!(log.contains("warning: unused variable"), "{}", log);
+        assert!(log.contains("error: unresolved reference: foo"), "{}", log);
+        assert!(!log.contains("bar"), "{}", log);
+        assert!(!toolchain.path().join("finished").exists());
+    }
+
+    #[test]
+    fn ir_inliner_passes_the_flag() {
+        let toolchain = FakeToolchain::new("2.0.0", "");
+        let mut build = toolchain.build();
+        build.ir_inliner(true);
+        assert!(compile_args(&build, "out")
+            .iter()
+            .any(|arg| arg == "-Xir-inliner"));
+        let retry = build.command("out", Backend::K1).unwrap();
+        assert!(!retry.get_args().any(|arg| arg == "-Xir-inliner"));
+
+        build.backend(Backend::K1);
+        assert!(configuration_error(&build).contains("`ir_inliner` requires the K2 frontend"));
+
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build.ir_inliner(true);
+        let error = build.command("out", build.backend).unwrap_err();
+        assert!(error.to_string().contains("does not support -Xir-inliner"));
+    }
+
+    #[test]
+    fn compile_and_archive_lays_out_the_entries() {
+        if !has_jar() {
+            return;
+        }
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"
+mkdir -p "$out/com/example" "$out/com/other"
+printf 'main' > "$out/com/example/MainKt.class"
+printf 'util' > "$out/com/other/Util.class""#,
+        );
+        let classes = toolchain.path().join("classes");
+        let archive = toolchain.path().join("app.zip");
+        let build = toolchain.build();
+
+        let path = build
+            .compile_and_archive(
+                &classes,
+                &archive,
+                ArchiveLayout::Prefix("BOOT-INF/classes".into()),
+            )
+            .unwrap();
+        assert_eq!(path, archive);
+        assert_eq!(
+            build.jar_tool(None).list(&archive).unwrap(),
+            [
+                "BOOT-INF/classes/com/example/MainKt.class",
+                "BOOT-INF/classes/com/other/Util.class"
+            ]
+        );
+
+        build
+            .compile_and_archive(&classes, &archive, ArchiveLayout::Flatten)
+            .unwrap();
+        assert_eq!(
+            build.jar_tool(None).list(&archive).unwrap(),
+            ["MainKt.class", "Util.class"]
+        );
+    }
+
+    #[test]
+    fn with_toolchain_selects_the_compiler_and_runtime() {
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let build = toolchain.build();
+        let cmd = build.command("out", build.backend).unwrap();
+        assert_eq!(
+            Path::new(cmd.get_program()),
+            toolchain.path().join("bin/kotlinc-jvm")
+        );
+
+        let classes = toolchain.path().join("classes");
+        build.compile(&classes.to_string_lossy()).unwrap();
+        let error = build
+            .jar_from_classes(&classes, &toolchain.path().join("out.jar"), true)
+            .unwrap_err();
+        assert!(error.to_string().contains("has no lib/kotlin-stdlib.jar"));
+
+        let mut build = Build::new();
+        build.with_toolchain(toolchain.path().join("bin"));
+        let problems = build.configuration_problems();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].to_string().contains("has no `bin` directory"));
+        assert!(problems[1].to_string().contains("has no `lib` directory"));
+    }
+
+    #[test]
+    fn emit_jvm_type_annotations_passes_the_flag() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        let has_flag = |build: &Build| {
+            compile_args(build, "out")
+                .iter()
+                .any(|arg| arg == "-Xemit-jvm-type-annotations")
+        };
+        assert!(!has_flag(&build));
+        build.emit_jvm_type_annotations(true);
+        assert!(has_flag(&build));
+    }
+
+    #[test]
+    fn module_path_entries_use_the_module_flag() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let modules = toolchain.path().join("lib");
+        let jar = toolchain.path().join("app.jar");
+        std::fs::write(&jar, "").unwrap();
+
+        let mut build = toolchain.build();
+        build.module_path(&modules).module_path(&jar);
+        let args = compile_args(&build, "out");
+        assert!(args.contains(&format!(
+            "-Xmodule-path={}:{}",
+            modules.display(),
+            jar.display()
+        )));
+        assert!(!args.iter().any(|arg| arg == "-cp"));
+
+        build.module_path(toolchain.path().join("missing.jar"));
+        assert!(configuration_error(&build).contains("missing.jar does not exist"));
+    }
+
+    #[test]
+    fn compile_with_diagnostics_outcomes() {
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let output = toolchain.path().join("clean");
+        let diagnostics = toolchain
+            .build()
+            .compile_with_diagnostics(&output.to_string_lossy())
+            .unwrap();
+        assert_eq!(diagnostics, []);
+
+        let toolchain = FakeToolchain::new(
+            "1.9.22",
+            r#"echo "src/A.kt:2:1: error: unresolved reference: foo" >&2; exit 1"#,
+        );
+        let diagnostics = toolchain.build().compile_with_diagnostics("out").unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+
+        let compiler = toolchain::compiler_in(toolchain.path());
+        std::fs::remove_file(compiler).unwrap();
+        let error = toolchain
+            .build()
+            .compile_with_diagnostics("out")
+            .unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ToolNotFound);
+
+        let toolchain = FakeToolchain::new("1.9.22", "exit 1");
+        let error = toolchain
+            .build()
+            .compile_with_diagnostics("out")
+            .unwrap_err();
+        assert_eq!(error.kind(), &ErrorKind::ToolExecError);
+    }
+
+    #[test]
+    fn common_dir_adds_nested_common_sources() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let common = toolchain.path().join("common");
+        std::fs::create_dir_all(common.join("com/example/util")).unwrap();
+        for file in [
+            "com/example/Api.kt",
+            "com/example/util/Util.kt",
+            "README.md",
+        ] {
+            std::fs::write(common.join(file), "").unwrap();
+        }
+        let api = common.join("com/example/Api.kt");
+        let util = common.join("com/example/util/Util.kt");
+
+        let mut build = toolchain.build();
+        build.common_dir(&common).unwrap();
+        assert_eq!(build.get_files(), [api.clone(), util.clone()]);
+
+        let args = compile_args(&build, "out");
+        assert!(args.iter().any(|arg| arg == "-Xmulti-platform"));
+        assert!(args.contains(&format!(
+            "-Xcommon-sources={},{}",
+            api.display(),
+            util.display()
+        )));
+
+        let Err(error) = build.common_dir(toolchain.path().join("missing")) else {
+            panic!("reading a missing common directory succeeded");
+        };
+        assert!(error
+            .to_string()
+            .contains("Failed to read common directory"));
+    }
+
+    #[test]
+    fn canonical_commands_ignore_the_configuration_order() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut first = toolchain.build();
+        first
+            .file("Main.kt")
+            .experimental_flag(ExperimentalFlag::NoParamAssertions)
+            .experimental_flag(ExperimentalFlag::AllowResultReturnType)
+            .friend_path("b.jar")
+            .friend_path("a.jar")
+            .dump_phase("Inline")
+            .dump_phase("Deserialize");
+        let mut second = toolchain.build();
+        second
+            .dump_phase("Deserialize")
+            .dump_phase("Inline")
+            .friend_path("a.jar")
+            .friend_path("b.jar")
+            .experimental_flag(ExperimentalFlag::AllowResultReturnType)
+            .experimental_flag(ExperimentalFlag::NoParamAssertions)
+            .file("Main.kt");
+        assert_ne!(compile_args(&first, "out"), compile_args(&second, "out"));
+
+        first.canonical_command(true);
+        second.canonical_command(true);
+        assert_eq!(compile_args(&first, "out"), compile_args(&second, "out"));
+    }
+
+    #[test]
+    fn launcher_scripts_run_the_jar() {
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let jar = toolchain.path().join("app dir/app.jar");
+        std::fs::create_dir_all(jar.parent().unwrap()).unwrap();
+        std::fs::write(&jar, "").unwrap();
+        let java_home = toolchain.path().join("jdk");
+        std::fs::create_dir_all(java_home.join("bin")).unwrap();
+        let java = java_home.join("bin/java");
+        std::fs::write(&java, "#!/bin/sh\nfor arg; do echo \"[$arg]\"; done\n").unwrap();
+        std::fs::set_permissions(&java, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let script = toolchain.path().join("app");
+
+        let mut build = toolchain.build();
+        build.java_home(&java_home);
+        build
+            .emit_launcher_script(&jar, &script, "com.example.MainKt")
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&script).unwrap(),
+            format!(
+                "#!/bin/sh\nexec {} -cp '{}' com.example.MainKt \"$@\"\n",
+                java.display(),
+                jar.display()
+            )
+        );
+        let mode = std::fs::metadata(&script).unwrap().permissions().mode();
+        assert_eq!(mode & 0o111, 0o111);
+
+        let output = Command::new(&script).arg("a b").output().unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!("[-cp]\n[{}]\n[com.example.MainKt]\n[a b]\n", jar.display())
+        );
+
+        let error = build
+            .emit_launcher_script(&jar, &script, "com.example.Main Kt")
+            .unwrap_err();
+        assert!(error.to_string().contains("is not a valid main class name"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn build_round_trips_through_serde() {
+        let mut build = Build::new();
+        build
+            .file("src/Main.kt")
+            .file("src/Util.kt")
+            .classpath("libs/a.jar")
+            .classpath("libs/b.jar")
+            .backend(Backend::K2)
+            .language_version(KotlinVersion::Exact(1, 9))
+            .include_runtime(true)
+            .no_reflect(true)
+            .heartbeat(Some(Duration::from_secs(30)))
+            .raw_args_after_files(vec!["-Xno-call-assertions".into()])
+            .after_compile(|_| Ok(()));
+
+        let json = serde_json::to_string(&build).unwrap();
+        let restored: Build = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(restored.get_files(), build.get_files());
+        assert_eq!(restored.get_classpath(), build.get_classpath());
+        assert_eq!(restored.get_backend(), Backend::K2);
+        assert_eq!(
+            restored.get_language_version(),
+            Some(KotlinVersion::Exact(1, 9))
+        );
+        assert!(restored.get_include_runtime());
+        assert!(restored.get_no_reflect());
+        assert_eq!(restored.get_heartbeat(), Some(Duration::from_secs(30)));
+        assert_eq!(
+            restored.get_raw_args_after_files(),
+            build.get_raw_args_after_files()
+        );
+        assert!(restored.after_compile.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn language_version_conflicts_only_with_backends_that_set_it() {
+        let language_version = |args: &[String]| {
+            let index = args.iter().position(|arg| arg == "-language-version")?;
+            Some(args[index + 1].clone())
+        };
+
+        let toolchain = FakeToolchain::new("2.0.0", "");
+        let mut build = toolchain.build();
+        build
+            .backend(Backend::K2)
+            .language_version(KotlinVersion::Exact(2, 0));
+        assert_eq!(
+            language_version(&compile_args(&build, "out")),
+            Some("2.0".into())
+        );
+
+        let toolchain = FakeToolchain::new("1.8.0", "");
+        let mut build = toolchain.build();
+        build
+            .backend(Backend::K2)
+            .language_version(KotlinVersion::Exact(1, 8));
+        let args = compile_args(&build, "out");
+        assert!(args.iter().any(|arg| arg == "-Xuse-k2"));
+        assert_eq!(language_version(&args), Some("1.8".into()));
+
+        let toolchain = FakeToolchain::new("1.9.22", "");
+        let mut build = toolchain.build();
+        build
+            .backend(Backend::K2)
+            .language_version(KotlinVersion::Exact(1, 9));
+        assert!(configuration_error(&build)
+            .contains("cannot be combined with the K2 backend on kotlinc 1.9.22"));
+    }
+
+    #[test]
+    fn cache_key_skips_every_compile_entry_point() {
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let output = toolchain.path().join("out");
+        let output = output.to_string_lossy();
+
+        let mut build = toolchain.build();
+        build.cache_key("generator 1");
+        build.compile_checked(&output).unwrap();
+        let outcome = build.compile_checked(&output).unwrap();
+        assert_eq!(toolchain.invocations().len(), 1);
+        assert!(outcome.warnings.is_empty());
+
+        let mut sink = vec![];
+        build.compile_to_writer(&output, &mut sink).unwrap();
+        assert!(build.compile_with_outputs(&output).unwrap().is_empty());
+        assert_eq!(toolchain.invocations().len(), 1);
+        assert!(sink.is_empty());
     }
 }
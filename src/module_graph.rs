@@ -0,0 +1,245 @@
+//! Compiling several modules that depend on each other's outputs
+
+use std::{collections::HashMap, path::PathBuf, thread};
+
+use crate::command_helpers::{Error, ErrorKind};
+use crate::Build;
+
+struct Module {
+    name: String,
+    build: Build,
+    output: String,
+}
+
+/// A set of modules, each compiled by its own [`Build`], where modules can depend on the outputs
+/// of others.
+///
+/// [`ModuleGraph::compile_all`] compiles every module after the modules it depends on, with the
+/// outputs of all its direct and transitive dependencies added to its classpath. Modules that
+/// don't depend on each other are compiled in parallel.
+#[derive(Default)]
+pub struct ModuleGraph {
+    modules: Vec<Module>,
+    /// Pairs of a module and a module it depends on.
+    dependencies: Vec<(String, String)>,
+}
+
+impl ModuleGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a module named `name`, compiled by `build` to `output`.
+    pub fn module<S: Into<String>>(&mut self, name: S, build: Build, output: &str) -> &mut Self {
+        self.modules.push(Module {
+            name: name.into(),
+            build,
+            output: output.to_owned(),
+        });
+        self
+    }
+
+    /// Declares that the module `module` depends on the output of the module `dependency`. Both
+    /// have to be added with [`ModuleGraph::module`] before compiling.
+    pub fn dependency(&mut self, module: &str, dependency: &str) -> &mut Self {
+        self.dependencies
+            .push((module.to_owned(), dependency.to_owned()));
+        self
+    }
+
+    /// Compiles every module in dependency order. Stops after the first set of independent
+    /// modules in which a module failed, returning that module's error.
+    pub fn compile_all(&mut self) -> Result<(), Error> {
+        let edges = self.edges()?;
+        let waves = self.waves(&edges)?;
+
+        for index in 0..self.modules.len() {
+            for dependency in transitive_dependencies(&edges, index) {
+                let output = PathBuf::from(&self.modules[dependency].output);
+                let build = &mut self.modules[index].build;
+                if !build.get_classpath().contains(&output) {
+                    build.classpath(output);
+                }
+            }
+        }
+
+        for wave in waves {
+            let modules = &self.modules;
+            let results = thread::scope(|scope| {
+                let handles = wave
+                    .iter()
+                    .map(|&index| {
+                        let module = &modules[index];
+                        scope.spawn(move || module.build.compile(&module.output))
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("compiling a module panicked"))
+                    .collect::<Vec<_>>()
+            });
+
+            for (&index, result) in wave.iter().zip(results) {
+                result.map_err(|e| {
+                    e.context(format_args!(
+                        "Failed to compile module `{}`",
+                        self.modules[index].name
+                    ))
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Groups the modules into waves, where every module only depends on modules of earlier
+    /// waves.
+    fn waves(&self, edges: &[Vec<usize>]) -> Result<Vec<Vec<usize>>, Error> {
+        let mut remaining = edges.iter().map(Vec::len).collect::<Vec<_>>();
+        let mut dependents = vec![vec![]; self.modules.len()];
+        for (index, dependencies) in edges.iter().enumerate() {
+            for &dependency in dependencies {
+                dependents[dependency].push(index);
+            }
+        }
+
+        let mut waves = vec![];
+        let mut wave = (0..self.modules.len())
+            .filter(|&index| remaining[index] == 0)
+            .collect::<Vec<_>>();
+        let mut compiled = 0;
+        while !wave.is_empty() {
+            compiled += wave.len();
+            let mut next = vec![];
+            for &index in &wave {
+                for &dependent in &dependents[index] {
+                    remaining[dependent] -= 1;
+                    if remaining[dependent] == 0 {
+                        next.push(dependent);
+                    }
+                }
+            }
+            waves.push(wave);
+            wave = next;
+        }
+
+        if compiled < self.modules.len() {
+            let cycle = self
+                .modules
+                .iter()
+                .zip(&remaining)
+                .filter(|(_, remaining)| **remaining > 0)
+                .map(|(module, _)| format!("`{}`", module.name))
+                .collect::<Vec<_>>();
+            return Err(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!(
+                    "Module dependencies form a cycle involving {}",
+                    cycle.join(", ")
+                ),
+            ));
+        }
+
+        Ok(waves)
+    }
+
+    /// Returns the indices of the modules each module directly depends on, checking that module
+    /// names are unique and that every dependency refers to a known module.
+    fn edges(&self) -> Result<Vec<Vec<usize>>, Error> {
+        let mut indices = HashMap::new();
+        for (index, module) in self.modules.iter().enumerate() {
+            if indices.insert(module.name.as_str(), index).is_some() {
+                return Err(Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!("Module `{}` is added more than once", module.name),
+                ));
+            }
+        }
+
+        let lookup = |name: &str| {
+            indices.get(name).copied().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!("Dependency declared on unknown module `{}`", name),
+                )
+            })
+        };
+
+        let mut edges = vec![vec![]; self.modules.len()];
+        for (module, dependency) in &self.dependencies {
+            let (module, dependency) = (lookup(module)?, lookup(dependency)?);
+            if !edges[module].contains(&dependency) {
+                edges[module].push(dependency);
+            }
+        }
+        Ok(edges)
+    }
+}
+
+/// Returns the indices of every module `index` depends on, directly or transitively.
+fn transitive_dependencies(edges: &[Vec<usize>], index: usize) -> Vec<usize> {
+    let mut found = vec![];
+    let mut pending = vec![index];
+    while let Some(current) = pending.pop() {
+        for &dependency in &edges[current] {
+            if !found.contains(&dependency) {
+                found.push(dependency);
+                pending.push(dependency);
+            }
+        }
+    }
+    found
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tests::{FakeToolchain, WRITE_CLASS};
+
+    #[test]
+    fn compiles_dependencies_first() {
+        let toolchain = FakeToolchain::new("1.9.22", WRITE_CLASS);
+        let core = toolchain.path().join("core");
+        let app = toolchain.path().join("app");
+
+        let mut graph = ModuleGraph::new();
+        graph
+            .module("app", toolchain.build(), &app.to_string_lossy())
+            .module("core", toolchain.build(), &core.to_string_lossy())
+            .dependency("app", "core");
+        graph.compile_all().unwrap();
+
+        let invocations = toolchain.invocations();
+        assert_eq!(invocations.len(), 2);
+        assert!(invocations[0].ends_with(&format!("-d {}", core.display())));
+        assert!(invocations[1].contains(&format!("-cp {} ", core.display())));
+        assert!(invocations[1].ends_with(&format!("-d {}", app.display())));
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let mut graph = ModuleGraph::new();
+        graph
+            .module("a", Build::new(), "a")
+            .module("b", Build::new(), "b")
+            .module("c", Build::new(), "c")
+            .dependency("a", "b")
+            .dependency("b", "a")
+            .dependency("c", "a");
+
+        let error = graph.compile_all().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "InvalidConfiguration: Module dependencies form a cycle involving `a`, `b`, `c`"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_modules() {
+        let mut graph = ModuleGraph::new();
+        graph.module("a", Build::new(), "a").dependency("a", "b");
+
+        let error = graph.compile_all().unwrap_err();
+        assert!(error.to_string().contains("unknown module `b`"));
+    }
+}
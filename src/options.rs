@@ -0,0 +1,506 @@
+//! Typed values for kotlinc options
+
+use std::path::{Path, PathBuf};
+
+use crate::command_helpers::Error;
+use crate::version::CompilerVersion;
+
+/// The compiler frontend used by kotlinc.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Backend {
+    /// Whatever the compiler uses by default: K1 before Kotlin 2.0, K2 since.
+    #[default]
+    Default,
+    K1,
+    K2,
+}
+
+impl Backend {
+    /// Returns the flags selecting this backend on the given compiler version, or `None` if the
+    /// compiler doesn't support it.
+    pub(crate) fn flags(&self, version: CompilerVersion) -> Option<&'static [&'static str]> {
+        const KOTLIN_2_0: CompilerVersion = CompilerVersion::new(2, 0, 0);
+        const KOTLIN_1_9: CompilerVersion = CompilerVersion::new(1, 9, 0);
+        const KOTLIN_1_7: CompilerVersion = CompilerVersion::new(1, 7, 0);
+
+        match self {
+            Backend::Default => Some(&[]),
+            Backend::K1 if version >= KOTLIN_2_0 => Some(&["-language-version", "1.9"]),
+            Backend::K1 => Some(&[]),
+            Backend::K2 if version >= KOTLIN_2_0 => Some(&[]),
+            Backend::K2 if version >= KOTLIN_1_9 => Some(&["-language-version", "2.0"]),
+            Backend::K2 if version >= KOTLIN_1_7 => Some(&["-Xuse-k2"]),
+            Backend::K2 => None,
+        }
+    }
+}
+
+/// A Kotlin language or API version, as passed to `-language-version` and `-api-version`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum KotlinVersion {
+    /// The newest version the installed compiler supports, which is the `major.minor` of the
+    /// compiler itself and is resolved when compiling.
+    LatestStable,
+    /// A fixed version given as major and minor, such as `Exact(1, 9)` for `1.9`.
+    Exact(u32, u32),
+}
+
+impl KotlinVersion {
+    /// Returns the version as kotlinc expects it, querying the compiler version only when needed.
+    pub(crate) fn resolve<F>(&self, compiler_version: F) -> Result<String, Error>
+    where
+        F: FnOnce() -> Result<CompilerVersion, Error>,
+    {
+        Ok(match self {
+            KotlinVersion::LatestStable => {
+                let version = compiler_version()?;
+                format!("{}.{}", version.major, version.minor)
+            }
+            KotlinVersion::Exact(major, minor) => format!("{}.{}", major, minor),
+        })
+    }
+}
+
+/// Where the Kotlin runtime jars on the classpath, `kotlin-stdlib*.jar` and `kotlin-reflect*.jar`,
+/// are placed relative to the other entries. When the same class is found in several entries, the
+/// first one wins.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ClasspathOrder {
+    /// Entries are passed in the order they were added.
+    #[default]
+    AsAdded,
+    /// The other entries come before the Kotlin runtime jars.
+    UserFirst,
+    /// The Kotlin runtime jars come before the other entries.
+    StdlibFirst,
+}
+
+impl ClasspathOrder {
+    /// Orders `entries`, keeping the relative order within the runtime jars and the other entries.
+    pub(crate) fn sort<'a>(&self, entries: &'a [PathBuf]) -> Vec<&'a PathBuf> {
+        let (runtime, user): (Vec<_>, Vec<_>) =
+            entries.iter().partition(|entry| is_kotlin_runtime(entry));
+        match self {
+            ClasspathOrder::AsAdded => entries.iter().collect(),
+            ClasspathOrder::UserFirst => user.into_iter().chain(runtime).collect(),
+            ClasspathOrder::StdlibFirst => runtime.into_iter().chain(user).collect(),
+        }
+    }
+}
+
+fn is_kotlin_runtime(entry: &Path) -> bool {
+    entry
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| {
+            name.ends_with(".jar")
+                && (name.starts_with("kotlin-stdlib") || name.starts_with("kotlin-reflect"))
+        })
+}
+
+/// Which Kotlin runtime jars [`crate::Build::jar_from_classes`] bundles.
+///
+/// Since Kotlin 1.8 the `jdk7` and `jdk8` extensions are part of `kotlin-stdlib` itself and their
+/// jars are nearly empty, so both variants produce jars of about the same size. With older
+/// compilers [`RuntimeVariant::Minimal`] saves the extensions' size, at the cost of failing at
+/// runtime if code uses APIs from them, such as `kotlin.streams` or `use` on `AutoCloseable`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RuntimeVariant {
+    /// `kotlin-stdlib` together with the `kotlin-stdlib-jdk7` and `kotlin-stdlib-jdk8`
+    /// extensions, where the distribution has them.
+    #[default]
+    Full,
+    /// Only `kotlin-stdlib`.
+    Minimal,
+}
+
+impl RuntimeVariant {
+    /// The jars in the `lib` directory of the Kotlin distribution making up this variant. Only
+    /// the first one is required to exist.
+    pub(crate) fn libraries(&self) -> &'static [&'static str] {
+        match self {
+            RuntimeVariant::Full => &[
+                "kotlin-stdlib.jar",
+                "kotlin-stdlib-jdk7.jar",
+                "kotlin-stdlib-jdk8.jar",
+            ],
+            RuntimeVariant::Minimal => &["kotlin-stdlib.jar"],
+        }
+    }
+}
+
+/// How compiler diagnostics are forwarded while compiling.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DiagnosticFormat {
+    /// Every line kotlinc prints to stderr becomes a `cargo:warning`, so cargo shows it.
+    #[default]
+    CargoWarning,
+    /// Lines are printed to stdout unchanged, where cargo only shows them for a failing build
+    /// script.
+    Plain,
+    /// Diagnostics are printed to stdout as GitHub Actions workflow commands, such as
+    /// `::error file=src/A.kt,line=3,col=5::message`, so CI shows them as annotations. Other
+    /// lines are printed unchanged.
+    GithubActions,
+}
+
+/// How nullability annotations on Java declarations are enforced, used for
+/// `-Xjspecify-annotations`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NullabilityMode {
+    /// The annotations are ignored and the annotated types are platform types.
+    Ignore,
+    /// Violations of the annotated nullability are reported as warnings.
+    Warning,
+    /// Violations of the annotated nullability are reported as errors.
+    Strict,
+}
+
+impl NullabilityMode {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            NullabilityMode::Ignore => "ignore",
+            NullabilityMode::Warning => "warn",
+            NullabilityMode::Strict => "strict",
+        }
+    }
+}
+
+/// How string concatenations and templates are compiled, selected through `-Xstring-concat`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StringConcatMode {
+    /// `invokedynamic` through `StringConcatFactory.makeConcatWithConstants`, which is what
+    /// kotlinc uses by default when targeting JVM 9 or newer.
+    IndyWithConstants,
+    /// `invokedynamic` through `StringConcatFactory.makeConcat`.
+    Indy,
+    /// A `StringBuilder` chain, as used for JVM 1.8.
+    Inline,
+}
+
+impl StringConcatMode {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            StringConcatMode::IndyWithConstants => "indy-with-constants",
+            StringConcatMode::Indy => "indy",
+            StringConcatMode::Inline => "inline",
+        }
+    }
+
+    /// The first compiler version accepting the mode.
+    pub(crate) fn since(&self) -> CompilerVersion {
+        CompilerVersion::new(1, 4, 20)
+    }
+}
+
+/// How calls to Kotlin's `assert()` are compiled, selected through `-Xassertions`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AssertionsMode {
+    /// Assertions follow the JVM's `-ea`/`-da` switches for the class, like Java's `assert`.
+    Jvm,
+    /// Assertions are always checked, regardless of how the JVM was started.
+    Always,
+    /// Assertions are compiled out and never checked.
+    Never,
+}
+
+impl AssertionsMode {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            AssertionsMode::Jvm => "jvm",
+            AssertionsMode::Always => "always-enable",
+            AssertionsMode::Never => "always-disable",
+        }
+    }
+}
+
+/// A narrow experimental kotlinc flag that doesn't warrant a builder method of its own.
+///
+/// Flags that aren't covered here can still be passed with [`crate::Build::raw_args_before_files`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ExperimentalFlag {
+    /// `-Xallow-result-return-type`: allows `kotlin.Result` as a return type.
+    AllowResultReturnType,
+    /// `-Xallow-kotlin-package`: allows declarations in the `kotlin` package.
+    AllowKotlinPackage,
+    /// `-Xcontext-receivers`: enables context receivers.
+    ContextReceivers,
+    /// `-Xinline-classes`: enables inline classes.
+    InlineClasses,
+    /// `-Xno-call-assertions`: omits null checks for calls to Java methods.
+    NoCallAssertions,
+    /// `-Xno-param-assertions`: omits null checks for parameters of public functions.
+    NoParamAssertions,
+    /// `-Xno-receiver-assertions`: omits null checks for receivers of extension functions.
+    NoReceiverAssertions,
+    /// `-Xsuppress-version-warnings`: silences warnings about language and API versions.
+    SuppressVersionWarnings,
+}
+
+impl ExperimentalFlag {
+    /// The argument passed to kotlinc.
+    pub(crate) fn flag(&self) -> &'static str {
+        match self {
+            ExperimentalFlag::AllowResultReturnType => "-Xallow-result-return-type",
+            ExperimentalFlag::AllowKotlinPackage => "-Xallow-kotlin-package",
+            ExperimentalFlag::ContextReceivers => "-Xcontext-receivers",
+            ExperimentalFlag::InlineClasses => "-Xinline-classes",
+            ExperimentalFlag::NoCallAssertions => "-Xno-call-assertions",
+            ExperimentalFlag::NoParamAssertions => "-Xno-param-assertions",
+            ExperimentalFlag::NoReceiverAssertions => "-Xno-receiver-assertions",
+            ExperimentalFlag::SuppressVersionWarnings => "-Xsuppress-version-warnings",
+        }
+    }
+
+    /// The first compiler version accepting the flag.
+    pub(crate) fn since(&self) -> CompilerVersion {
+        match self {
+            ExperimentalFlag::AllowResultReturnType | ExperimentalFlag::InlineClasses => {
+                CompilerVersion::new(1, 3, 0)
+            }
+            ExperimentalFlag::ContextReceivers => CompilerVersion::new(1, 6, 20),
+            ExperimentalFlag::SuppressVersionWarnings => CompilerVersion::new(1, 4, 0),
+            ExperimentalFlag::AllowKotlinPackage
+            | ExperimentalFlag::NoCallAssertions
+            | ExperimentalFlag::NoParamAssertions
+            | ExperimentalFlag::NoReceiverAssertions => CompilerVersion::new(1, 0, 0),
+        }
+    }
+}
+
+/// A type-inference toggle of kotlinc, grouped so the inference knobs are discoverable in one
+/// place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum InferenceFlag {
+    /// `-Xnew-inference`: enables the new inference algorithm on compilers that don't use it by
+    /// default.
+    NewInference,
+    /// `-Xinference-compatibility`: makes the new inference behave like the old one in known
+    /// corner cases.
+    InferenceCompatibility,
+    /// `-Xself-upper-bound-inference`: infers type arguments of self types from their upper
+    /// bounds.
+    SelfUpperBound,
+    /// `-Xenable-builder-inference`: applies builder inference to all lambdas without requiring
+    /// `@BuilderInference`.
+    EnableBuilderInference,
+    /// `-Xunrestricted-builder-inference`: lifts the restrictions on calls inside builder
+    /// inference lambdas.
+    UnrestrictedBuilderInference,
+}
+
+impl InferenceFlag {
+    /// The argument passed to kotlinc.
+    pub(crate) fn flag(&self) -> &'static str {
+        match self {
+            InferenceFlag::NewInference => "-Xnew-inference",
+            InferenceFlag::InferenceCompatibility => "-Xinference-compatibility",
+            InferenceFlag::SelfUpperBound => "-Xself-upper-bound-inference",
+            InferenceFlag::EnableBuilderInference => "-Xenable-builder-inference",
+            InferenceFlag::UnrestrictedBuilderInference => "-Xunrestricted-builder-inference",
+        }
+    }
+
+    /// The first compiler version accepting the flag.
+    pub(crate) fn since(&self) -> CompilerVersion {
+        match self {
+            InferenceFlag::NewInference => CompilerVersion::new(1, 3, 0),
+            InferenceFlag::InferenceCompatibility => CompilerVersion::new(1, 4, 0),
+            InferenceFlag::SelfUpperBound
+            | InferenceFlag::EnableBuilderInference
+            | InferenceFlag::UnrestrictedBuilderInference => CompilerVersion::new(1, 6, 0),
+        }
+    }
+}
+
+/// Returns whether the JVM knows `charset`, comparing names without case, dashes and
+/// underscores so that aliases such as `utf8` and `UTF_8` are accepted.
+pub(crate) fn is_known_charset(charset: &str) -> bool {
+    const KNOWN: &[&str] = &[
+        "utf8",
+        "utf16",
+        "utf16be",
+        "utf16le",
+        "utf32",
+        "utf32be",
+        "utf32le",
+        "usascii",
+        "ascii",
+        "iso88591",
+        "latin1",
+        "iso88592",
+        "iso88595",
+        "iso88597",
+        "iso88599",
+        "iso885913",
+        "iso885915",
+        "windows1250",
+        "windows1251",
+        "windows1252",
+        "windows1253",
+        "windows1254",
+        "windows1257",
+        "cp1250",
+        "cp1251",
+        "cp1252",
+        "cp437",
+        "ibm437",
+        "cp850",
+        "ibm850",
+        "koi8r",
+        "koi8u",
+        "shiftjis",
+        "sjis",
+        "windows31j",
+        "eucjp",
+        "euckr",
+        "iso2022jp",
+        "gbk",
+        "gb2312",
+        "gb18030",
+        "big5",
+        "macroman",
+    ];
+
+    let normalized = charset
+        .chars()
+        .filter(|c| !matches!(c, '-' | '_'))
+        .collect::<String>()
+        .to_ascii_lowercase();
+    KNOWN.contains(&normalized.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backend_flags_depend_on_the_compiler_version() {
+        let v = CompilerVersion::new;
+
+        assert_eq!(Backend::Default.flags(v(2, 1, 0)), Some(&[][..]));
+        assert_eq!(
+            Backend::K1.flags(v(2, 1, 0)),
+            Some(&["-language-version", "1.9"][..])
+        );
+        assert_eq!(Backend::K1.flags(v(1, 9, 22)), Some(&[][..]));
+        assert_eq!(Backend::K2.flags(v(2, 0, 0)), Some(&[][..]));
+        assert_eq!(
+            Backend::K2.flags(v(1, 9, 22)),
+            Some(&["-language-version", "2.0"][..])
+        );
+        assert_eq!(Backend::K2.flags(v(1, 7, 0)), Some(&["-Xuse-k2"][..]));
+        assert_eq!(Backend::K2.flags(v(1, 6, 21)), None);
+    }
+
+    #[test]
+    fn experimental_flags_map_to_their_arguments() {
+        assert_eq!(
+            ExperimentalFlag::AllowResultReturnType.flag(),
+            "-Xallow-result-return-type"
+        );
+        assert_eq!(
+            ExperimentalFlag::AllowKotlinPackage.flag(),
+            "-Xallow-kotlin-package"
+        );
+        assert_eq!(
+            ExperimentalFlag::ContextReceivers.flag(),
+            "-Xcontext-receivers"
+        );
+        assert_eq!(
+            ExperimentalFlag::ContextReceivers.since(),
+            CompilerVersion::new(1, 6, 20)
+        );
+    }
+
+    #[test]
+    fn classpath_order_moves_the_runtime_jars() {
+        let entries = [
+            "a.jar",
+            "lib/kotlin-stdlib-1.9.22.jar",
+            "classes",
+            "kotlin-reflect.jar",
+        ]
+        .map(PathBuf::from);
+        let sorted = |order: ClasspathOrder| {
+            order
+                .sort(&entries)
+                .into_iter()
+                .map(|entry| entry.to_str().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            sorted(ClasspathOrder::AsAdded),
+            [
+                "a.jar",
+                "lib/kotlin-stdlib-1.9.22.jar",
+                "classes",
+                "kotlin-reflect.jar"
+            ]
+        );
+        assert_eq!(
+            sorted(ClasspathOrder::UserFirst),
+            [
+                "a.jar",
+                "classes",
+                "lib/kotlin-stdlib-1.9.22.jar",
+                "kotlin-reflect.jar"
+            ]
+        );
+        assert_eq!(
+            sorted(ClasspathOrder::StdlibFirst),
+            [
+                "lib/kotlin-stdlib-1.9.22.jar",
+                "kotlin-reflect.jar",
+                "a.jar",
+                "classes"
+            ]
+        );
+    }
+
+    #[test]
+    fn latest_stable_resolves_to_the_compiler_version() {
+        let version = KotlinVersion::LatestStable
+            .resolve(|| Ok(CompilerVersion::new(2, 1, 10)))
+            .unwrap();
+        assert_eq!(version, "2.1");
+
+        let version = KotlinVersion::Exact(1, 9)
+            .resolve(|| panic!("exact versions don't query the compiler"))
+            .unwrap();
+        assert_eq!(version, "1.9");
+    }
+
+    #[test]
+    fn charset_names_ignore_case_and_separators() {
+        assert!(is_known_charset("UTF-8"));
+        assert!(is_known_charset("utf_8"));
+        assert!(is_known_charset("ISO-8859-1"));
+        assert!(!is_known_charset("UTF-9"));
+        assert!(!is_known_charset(""));
+    }
+
+    #[test]
+    fn inference_flags_map_to_their_arguments() {
+        assert_eq!(InferenceFlag::NewInference.flag(), "-Xnew-inference");
+        assert_eq!(
+            InferenceFlag::SelfUpperBound.flag(),
+            "-Xself-upper-bound-inference"
+        );
+        assert_eq!(
+            InferenceFlag::SelfUpperBound.since(),
+            CompilerVersion::new(1, 6, 0)
+        );
+    }
+}
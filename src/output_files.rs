@@ -0,0 +1,145 @@
+//! Parsing of the `-Xreport-output-files` report printed by kotlinc
+
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::diagnostics::Diagnostic;
+
+/// Parses the report kotlinc prints for `-Xreport-output-files` into a map from each source
+/// file to the output files generated from it.
+///
+/// Every generated file is reported as a separate message of the form:
+///
+/// ```text
+/// output: Output:
+/// /path/to/out/com/example/Foo.class
+/// Sources:
+/// /path/to/src/com/example/Foo.kt
+/// ```
+///
+/// Lines that are not part of such a message (ordinary diagnostics, for example) are ignored.
+pub(crate) fn parse(report: &str) -> HashMap<PathBuf, Vec<PathBuf>> {
+    enum State {
+        Idle,
+        Output,
+        Sources(PathBuf),
+    }
+
+    let mut outputs: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    let mut state = State::Idle;
+
+    for line in report.lines() {
+        let line = line.trim_end();
+        let line = line.strip_prefix("output: ").unwrap_or(line);
+
+        state = match (state, line) {
+            (_, "Output:") => State::Output,
+            (State::Output, output) if !output.is_empty() => State::Sources(output.into()),
+            (State::Sources(output), "Sources:") => State::Sources(output),
+            (State::Sources(output), source) if is_source_file(source) => {
+                outputs
+                    .entry(source.into())
+                    .or_default()
+                    .push(output.clone());
+                State::Sources(output)
+            }
+            _ => State::Idle,
+        };
+    }
+
+    outputs
+}
+
+/// Recognizes the lines of the report while kotlinc's output is forwarded, so they can be kept
+/// out of the build log.
+#[derive(Default)]
+pub(crate) struct ReportLines {
+    in_report: bool,
+}
+
+impl ReportLines {
+    /// Returns whether `line` belongs to a report message, which lasts from its `Output:` line
+    /// until the next diagnostic.
+    pub(crate) fn contains(report: Option<&mut ReportLines>, line: &[u8]) -> bool {
+        let Some(report) = report else {
+            return false;
+        };
+
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end();
+        if line.strip_prefix("output: ").unwrap_or(line) == "Output:" {
+            report.in_report = true;
+        } else if report.in_report && Diagnostic::parse(line).is_some() {
+            report.in_report = false;
+        }
+        report.in_report
+    }
+}
+
+fn is_source_file(line: &str) -> bool {
+    [".kt", ".kts", ".java"]
+        .iter()
+        .any(|extension| line.ends_with(extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    const REPORT: &str = "\
+output: Output:
+/out/com/example/Foo.class
+Sources:
+/src/com/example/Foo.kt
+warning: parameter 'x' is never used
+output: Output:
+/out/com/example/FooKt.class
+Sources:
+/src/com/example/Foo.kt
+output: Output:
+/out/com/example/Bar.class
+Sources:
+/src/com/example/Bar.kt
+/src/com/example/Baz.kt
+";
+
+    #[test]
+    fn parses_every_output_of_a_source() {
+        let outputs = parse(REPORT);
+
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(
+            outputs[Path::new("/src/com/example/Foo.kt")],
+            [
+                PathBuf::from("/out/com/example/Foo.class"),
+                PathBuf::from("/out/com/example/FooKt.class"),
+            ]
+        );
+        assert_eq!(
+            outputs[Path::new("/src/com/example/Bar.kt")],
+            [PathBuf::from("/out/com/example/Bar.class")]
+        );
+        assert_eq!(
+            outputs[Path::new("/src/com/example/Baz.kt")],
+            [PathBuf::from("/out/com/example/Bar.class")]
+        );
+    }
+
+    #[test]
+    fn ignores_unrelated_lines() {
+        assert!(parse("warning: parameter 'x' is never used\nSources:\n/src/Foo.kt\n").is_empty());
+    }
+
+    #[test]
+    fn report_lines_end_at_the_next_diagnostic() {
+        let mut report = ReportLines::default();
+        let contained = REPORT
+            .lines()
+            .filter(|line| ReportLines::contains(Some(&mut report), line.as_bytes()))
+            .count();
+
+        assert_eq!(contained, REPORT.lines().count() - 1);
+        assert!(!ReportLines::contains(None, b"output: Output:"));
+    }
+}
@@ -0,0 +1,254 @@
+//! Discovery and inspection of Kotlin source files
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::command_helpers::{
+    run_and_capture_output, CargoOutput, Error, ErrorKind, OutputKind, Stream,
+};
+use crate::fs_helpers::walk_files;
+
+/// Returns every `.kt` file below `root`, and every `.java` file as well if `include_java` is
+/// set, sorted by path.
+pub(crate) fn source_files(root: &Path, include_java: bool) -> Result<Vec<PathBuf>, Error> {
+    Ok(walk_files(root)?
+        .into_iter()
+        .filter(|file| {
+            file.extension()
+                .is_some_and(|extension| extension == "kt" || (include_java && extension == "java"))
+        })
+        .collect())
+}
+
+/// Returns every `.kt` file tracked by the git repository at `repo_root`, as listed by
+/// `git ls-files`, sorted by path.
+pub(crate) fn git_tracked_files(
+    repo_root: &Path,
+    cargo_output: &CargoOutput,
+) -> Result<Vec<PathBuf>, Error> {
+    let program = Path::new("git");
+    let mut cmd = Command::new(program);
+    // Keeps non-ASCII names unescaped, so they can be used as paths
+    cmd.arg("-C")
+        .arg(repo_root)
+        .args(["-c", "core.quotePath=false", "ls-files", "--", "*.kt"]);
+
+    // The listing is only inspected, never forwarded
+    let mut cargo_output = cargo_output.clone();
+    cargo_output.warnings = false;
+    cargo_output.output = OutputKind::Discard;
+    cargo_output.log_file = None;
+    cargo_output.fail_fast = false;
+    let (status, output) = run_and_capture_output(&mut cmd, program, &cargo_output, None);
+    if let Err(e) = status {
+        if output
+            .lines_of(Stream::Stderr)
+            .any(|line| line.contains("not a git repository"))
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidConfiguration,
+                format!("{} is not inside a git repository", repo_root.display()),
+            ));
+        }
+        return Err(e);
+    }
+
+    let mut files = output
+        .lines_of(Stream::Stdout)
+        .filter(|file| !file.is_empty())
+        .map(|file| repo_root.join(file))
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+/// Returns the package declared by a source file, which is empty for the default package.
+pub(crate) fn declared_package(source: &Path) -> Result<String, Error> {
+    let contents = fs::read_to_string(source)?;
+    let mut in_block_comment = false;
+
+    for line in contents.lines() {
+        let mut line = line.trim();
+
+        if in_block_comment {
+            match line.find("*/") {
+                Some(end) => {
+                    in_block_comment = false;
+                    line = line[end + 2..].trim();
+                }
+                None => continue,
+            }
+        }
+
+        if let Some(comment) = line.strip_prefix("/*") {
+            in_block_comment = !comment.contains("*/");
+            continue;
+        }
+
+        if line.is_empty() || line.starts_with("//") || line.starts_with("@file:") {
+            continue;
+        }
+
+        return Ok(line
+            .strip_prefix("package ")
+            .and_then(|package| package.split([';', '/']).next())
+            .map(|package| package.trim().replace('`', ""))
+            .unwrap_or_default());
+    }
+
+    Ok(String::new())
+}
+
+/// Checks that every file declares the package matching its directory relative to `root`, and
+/// returns a description of each file that doesn't.
+pub(crate) fn package_mismatches(root: &Path, files: &[PathBuf]) -> Result<Vec<String>, Error> {
+    let mut mismatches = vec![];
+
+    for file in files {
+        let expected = file
+            .parent()
+            .and_then(|dir| dir.strip_prefix(root).ok())
+            .map(|dir| {
+                dir.components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(".")
+            })
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidConfiguration,
+                    format!("{} is not inside {}", file.display(), root.display()),
+                )
+            })?;
+
+        let declared = declared_package(file)?;
+        if declared != expected {
+            mismatches.push(format!(
+                "{} declares package `{}` but is located in `{}`",
+                file.display(),
+                declared,
+                expected
+            ));
+        }
+    }
+
+    Ok(mismatches)
+}
+
+/// Returns the jar entry name of every source file, placing `files` in the directory of their
+/// declared package and the files below each of `roots` at their path relative to the root.
+pub(crate) fn source_entries(
+    files: &[PathBuf],
+    roots: &[PathBuf],
+    include_java: bool,
+) -> Result<Vec<(String, PathBuf)>, Error> {
+    let mut entries = vec![];
+
+    for file in files {
+        let name = file.file_name().unwrap_or_default().to_string_lossy();
+        let package = declared_package(file)?;
+        let entry = if package.is_empty() {
+            name.into_owned()
+        } else {
+            format!("{}/{}", package.replace('.', "/"), name)
+        };
+        entries.push((entry, file.clone()));
+    }
+
+    for root in roots {
+        for file in source_files(root, include_java)? {
+            let entry = file
+                .strip_prefix(root)
+                .unwrap_or(&file)
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            entries.push((entry, file));
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn declared_package_skips_comments_and_annotations() {
+        let dir = crate::unique_scratch_dir("kotlin-test").unwrap();
+        let source = dir.path().join("Main.kt");
+        fs::write(
+            &source,
+            "// Copyright\n/*\n * package wrong\n */\n@file:JvmName(\"Main\")\n\npackage com.`fun`.app\n",
+        )
+        .unwrap();
+
+        assert_eq!(declared_package(&source).unwrap(), "com.fun.app");
+
+        fs::write(&source, "fun main() {}\n").unwrap();
+        assert_eq!(declared_package(&source).unwrap(), "");
+    }
+
+    #[test]
+    fn package_mismatches_reports_misplaced_files() {
+        let dir = crate::unique_scratch_dir("kotlin-test").unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("com/example")).unwrap();
+        fs::write(root.join("com/example/Good.kt"), "package com.example\n").unwrap();
+        fs::write(root.join("com/example/Moved.kt"), "package com.other\n").unwrap();
+        fs::write(root.join("Top.kt"), "fun top() {}\n").unwrap();
+
+        let files = source_files(root, false).unwrap();
+        let mismatches = package_mismatches(root, &files).unwrap();
+
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].contains("Moved.kt declares package `com.other`"));
+        assert!(mismatches[0].ends_with("located in `com.example`"));
+    }
+
+    #[test]
+    fn git_tracked_files_skips_untracked_files() {
+        let git = |dir: &Path, args: &[&str]| {
+            Command::new("git")
+                .arg("-C")
+                .arg(dir)
+                .args(args)
+                .status()
+                .is_ok_and(|status| status.success())
+        };
+        let dir = crate::unique_scratch_dir("kotlin-test").unwrap();
+        let repo = dir.path().join("repo");
+        fs::create_dir_all(repo.join("src/nested")).unwrap();
+        if !git(&repo, &["init", "-q"]) {
+            return;
+        }
+        for file in [
+            "Main.kt",
+            "src/nested/Util.kt",
+            "src/Untracked.kt",
+            "README.md",
+        ] {
+            fs::write(repo.join(file), "").unwrap();
+        }
+        assert!(git(
+            &repo,
+            &["add", "Main.kt", "src/nested/Util.kt", "README.md"]
+        ));
+
+        let files = git_tracked_files(&repo, &CargoOutput::new()).unwrap();
+        assert_eq!(
+            files,
+            [repo.join("Main.kt"), repo.join("src/nested/Util.kt")]
+        );
+
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        let error = git_tracked_files(&outside, &CargoOutput::new()).unwrap_err();
+        assert!(error.to_string().contains("is not inside a git repository"));
+    }
+}
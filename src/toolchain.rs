@@ -0,0 +1,53 @@
+//! Discovery of the installed Kotlin compiler distribution
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// Finds the root of the Kotlin distribution providing kotlinc, which contains `bin/kotlinc` and
+/// the runtime libraries in `lib`.
+///
+/// `KOTLIN_HOME` is used if set. Otherwise `kotlinc-jvm` or `kotlinc` is looked up on `PATH`,
+/// following symlinks such as the ones package managers install into `/usr/bin`.
+pub(crate) fn kotlin_home() -> Option<PathBuf> {
+    if let Some(home) = env::var_os("KOTLIN_HOME").filter(|home| !home.is_empty()) {
+        return Some(PathBuf::from(home));
+    }
+
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .flat_map(|dir| executable_names().map(move |name| dir.join(name)))
+        .find(|candidate| candidate.is_file())
+        .and_then(|kotlinc| fs::canonicalize(kotlinc).ok())
+        .and_then(|kotlinc| Some(kotlinc.parent()?.parent()?.to_path_buf()))
+}
+
+/// Looks up the `kotlinc-jvm` executable on `PATH`.
+pub(crate) fn find_compiler() -> Option<PathBuf> {
+    let name = executable_names().next()?;
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Returns the `kotlinc-jvm` executable of the Kotlin distribution at `home`.
+pub(crate) fn compiler_in(home: &Path) -> PathBuf {
+    let name = executable_names().next().unwrap_or("kotlinc-jvm");
+    home.join("bin").join(name)
+}
+
+/// Returns the `lib/<name>` jar of the Kotlin distribution, if it exists.
+pub(crate) fn library(home: &Path, name: &str) -> Option<PathBuf> {
+    Some(home.join("lib").join(name)).filter(|jar| jar.is_file())
+}
+
+fn executable_names() -> impl Iterator<Item = &'static str> {
+    let names: &[&str] = if cfg!(windows) {
+        &["kotlinc-jvm.bat", "kotlinc.bat"]
+    } else {
+        &["kotlinc-jvm", "kotlinc"]
+    };
+    names.iter().copied()
+}
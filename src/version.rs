@@ -0,0 +1,46 @@
+//! Detection of the version of the Kotlin compiler
+
+use std::fmt;
+
+/// The version of a Kotlin compiler, used to pick flags the compiler understands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct CompilerVersion {
+    pub(crate) major: u32,
+    pub(crate) minor: u32,
+    pub(crate) patch: u32,
+}
+
+impl CompilerVersion {
+    pub(crate) const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parses the output of `kotlinc-jvm -version`, such as
+    /// `info: kotlinc-jvm 1.9.22 (JRE 17.0.9+9)`. Pre-release suffixes like `-RC2` are ignored.
+    pub(crate) fn parse(output: &str) -> Option<Self> {
+        output
+            .split_whitespace()
+            .skip_while(|word| !word.starts_with("kotlinc"))
+            .nth(1)
+            .and_then(Self::parse_number)
+    }
+
+    fn parse_number(version: &str) -> Option<Self> {
+        let version = version.split('-').next()?;
+        let mut parts = version.split('.').map(str::parse::<u32>);
+        let major = parts.next()?.ok()?;
+        let minor = parts.next()?.ok()?;
+        let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+        Some(Self::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for CompilerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}